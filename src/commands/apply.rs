@@ -1,9 +1,14 @@
-use std::{path::PathBuf, collections::HashMap, hash::Hash};
+use std::{path::PathBuf, collections::{HashMap, HashSet}};
 
 use clap::{Arg, ArgAction, value_parser};
 use tracing::{info_span, instrument};
 use tracing_batteries::prelude::opentelemetry::trace::SpanKind;
 
+use crate::core::config_value::ConfigValue;
+use crate::core::retry::RetryConfig;
+use crate::core::state::{PackageOutcome, StateJournal};
+use crate::core::template::Partial;
+
 use super::*;
 
 #[derive(Debug)]
@@ -27,6 +32,46 @@ impl Command for ApplyCommand {
                     .action(ArgAction::Set)
                     .value_parser(value_parser!(PathBuf))
                     .required(true))
+            .arg(Arg::new("package")
+                    .short('p')
+                    .long("package")
+                    .value_name("NAME")
+                    .help("Only include this package (and the packages it needs). May be provided multiple times.")
+                    .action(ArgAction::Append)
+                    .value_parser(value_parser!(String)))
+            .arg(Arg::new("exclude")
+                    .short('x')
+                    .long("exclude")
+                    .value_name("NAME")
+                    .help("Exclude this package. May be provided multiple times.")
+                    .action(ArgAction::Append)
+                    .value_parser(value_parser!(String)))
+            .arg(Arg::new("secrets-key")
+                    .long("secrets-key")
+                    .env("BUCKLE_SECRETS_KEY")
+                    .value_name("FILE")
+                    .help("The key file used to decrypt encrypted files in your secrets directories.")
+                    .action(ArgAction::Set)
+                    .value_parser(value_parser!(PathBuf)))
+            .arg(Arg::new("state-dir")
+                    .long("state-dir")
+                    .env("BUCKLE_STATE_DIR")
+                    .value_name("FOLDER")
+                    .help("The directory in which buckle records the outcome of previous runs.")
+                    .action(ArgAction::Set)
+                    .value_parser(value_parser!(PathBuf)))
+            .arg(Arg::new("task-timeout")
+                    .long("task-timeout")
+                    .env("BUCKLE_TASK_TIMEOUT")
+                    .value_name("SECONDS")
+                    .help("The number of seconds a task may run for before it is stopped, unless the task sets its own timeout.")
+                    .action(ArgAction::Set)
+                    .value_parser(value_parser!(u64)))
+            .arg(Arg::new("force")
+                    .short('f')
+                    .long("force")
+                    .help("Apply every package, even if its inputs have not changed since it was last applied successfully.")
+                    .action(ArgAction::SetTrue))
     }
 }
 
@@ -45,35 +90,100 @@ impl CommandRunnable for ApplyCommand {
                 })?;
 
         let mut output = crate::core::output::output();
+        let secrets_key = matches.get_one::<PathBuf>("secrets-key").map(|p| p.as_path());
 
-        let config = crate::core::config::load_all_config(&config_dir.join("config"))?;
+        let config = crate::core::config::load_all_config(&config_dir.join("config"), &RetryConfig::default(), &HashMap::new())?;
         for (key, val) in config.iter() {
             writeln!(output, " = config {key}={val}")?;
         }
 
-        let secrets = crate::core::config::load_all_config(&config_dir.join("secrets"))?;
+        let secrets = crate::core::config::load_all_secrets(&config_dir.join("secrets"), &RetryConfig::default(), secrets_key, &config)?;
         for (key, _val) in secrets.iter() {
             writeln!(output, " = secret {key}=******")?;
         }
 
-        let packages = crate::core::package::get_all_packages(&config_dir.join("packages"))?;
+        let partials = crate::core::template::get_partials(&config_dir.join("templates"))?;
+        for partial in partials.iter() {
+            writeln!(output, " = partial '{}'", partial.name)?;
+        }
 
+        let state_dir = matches
+            .get_one::<PathBuf>("state-dir")
+            .cloned()
+            .or_else(crate::core::state::default_state_dir)
+            .ok_or_else(|| {
+                errors::user(
+                    "Could not determine where buckle should store its state.",
+                    "Provide the --state-dir directory when running this command.",
+                )
+            })?;
+        let force = matches.get_flag("force");
+        let task_timeout = matches.get_one::<u64>("task-timeout").copied();
+
+        let mut state = StateJournal::load(&state_dir)?;
+
+        let include: Vec<String> = matches
+            .get_many::<String>("package")
+            .map(|v| v.cloned().collect())
+            .unwrap_or_default();
+        let exclude: Vec<String> = matches
+            .get_many::<String>("exclude")
+            .map(|v| v.cloned().collect())
+            .unwrap_or_default();
+
+        let (packages, graph) = crate::core::package::get_package_graph(&config_dir.join("packages"))?;
+        let packages = crate::core::package::select_packages(packages, &graph, &include, &exclude)?;
+
+        let mut applied = HashSet::new();
         for package in packages {
-            let mut retries = 0;
-            while retries <= package.retry.limit {
-                match self.apply_package(&config, &secrets, &package) {
-                    Ok(_) => {
-                        break;
-                    }
-                    Err(err) => {
-                        retries += 1;
-                        if package.retry.limit >= retries {
-                            return Err(err);
-                        }
-                        std::thread::sleep(package.retry.delay.into());
-                    }
-                }
+            let _span = info_span!("package.apply", "package.id"=%package.id).entered();
+
+            // The package's config is resolved before it is hashed, so that changes to the values
+            // returned by its config scripts cause it to be applied again.
+            let package_config = package.get_config(&config)?;
+            let mut merged_config = config.clone();
+            merged_config.extend(package_config.clone());
+
+            let mut context = merged_config.clone();
+            context.extend(secrets.clone());
+
+            let package_secrets = package.get_secrets(secrets_key, &context)?;
+            let mut merged_secrets = secrets.clone();
+            merged_secrets.extend(package_secrets.clone());
+
+            let hash = package.hash(&merged_config, &partials)?;
+            let needs_changed = package.needs.iter().any(|need| applied.contains(need));
+
+            if !force && !needs_changed && state.is_current(&package.id, &hash) {
+                writeln!(output)?;
+                writeln!(output, " = package '{}' (unchanged)", &package.id)?;
+                continue;
+            }
+
+            writeln!(output)?;
+            writeln!(output, " + package '{}'", package.id)?;
+
+            for (key, val) in package_config.iter() {
+                writeln!(output, "   = config {key}={val}")?;
+            }
+
+            for key in package_secrets.keys() {
+                writeln!(output, "   = secret {key}=******")?;
             }
+
+            let result = package.retry.run(&format!("package '{}'", package.id), || {
+                self.apply_package(&merged_config, &merged_secrets, &partials, &package, task_timeout)
+            });
+
+            if let Err(err) = result {
+                state.record(&package.id, &hash, PackageOutcome::Failure);
+                state.save()?;
+                return Err(err);
+            }
+
+            state.record(&package.id, &hash, PackageOutcome::Success);
+            applied.insert(package.id.clone());
+            state.save()?;
         }
 
         Ok(0)
@@ -81,47 +191,49 @@ impl CommandRunnable for ApplyCommand {
 }
 
 impl ApplyCommand {
-    fn apply_package(&self, config: &HashMap<String, String>, secrets: &HashMap<String, String>, package: &crate::core::package::Package) -> Result<(), crate::errors::Error> {
+    /// Applies a package's files and tasks, using its resolved `config` and `secrets` (which include
+    /// the global values it inherits).
+    fn apply_package(&self, config: &HashMap<String, ConfigValue>, secrets: &HashMap<String, ConfigValue>, partials: &[Partial], package: &crate::core::package::Package, task_timeout: Option<u64>) -> Result<(), crate::errors::Error> {
         let mut output = crate::core::output::output();
-        let _span = info_span!("package.apply", "package.id"=%package.id).entered();
 
-        writeln!(output)?;
-        writeln!(output, " + package '{}'", &package.id)?;
-
-        let mut config = config.clone();
-        for (key, val) in package.get_config()? {
-            writeln!(output, "   = config {key}={val}")?;
-            config.insert(key, val);
+        // Package partials are registered after the shared ones, so they take precedence.
+        let mut partials = partials.to_vec();
+        for partial in package.get_partials()? {
+            writeln!(output, "   = partial '{}'", partial.name)?;
+            partials.push(partial);
         }
 
-        let mut secrets = secrets.clone();
-        for (key, val) in package.get_secrets()? {
-            writeln!(output, "   = secret {key}=******")?;
-            secrets.insert(key, val);
-        }
+        let template_context = crate::core::context::template_context(package, config, secrets);
 
         let root_path = PathBuf::from("/");
         let files = package.get_files()?;
         for file in files {
-            let target_path = package
-                .files
-                .get(&file.group)
-                .map(|f| f.as_path())
-                .unwrap_or(&root_path);
+            let file = file.resolve_path(&template_context)?;
+            let group = package.files.get(&file.group);
+            let target_path = group.map(|g| g.path.as_path()).unwrap_or(&root_path);
+            let permissions = group
+                .map(|g| g.permissions_for(&file.relative_path))
+                .unwrap_or_default();
+
+            let state = file.apply(target_path, &permissions, &template_context, &partials, package.strict_templates.unwrap_or(false))?;
             writeln!(
                 output,
-                "   + {} '{}'",
+                "   + {} '{}' ({})",
                 if file.is_template { "template" } else { "file" },
-                target_path.join(&file.relative_path).display()
+                target_path.join(&file.relative_path).display(),
+                state
             )?;
-
-            file.apply(target_path, &config, &secrets)?;
         }
 
         let tasks = package.get_tasks()?;
-        for task in tasks {
-            writeln!(output, "   + task '{}'", &task.name)?;
-            task.run(&config, &secrets)?;
+        for mut task in tasks {
+            task.config.timeout = task.config.timeout.or(task_timeout);
+
+            let retry = task.config.retry.as_ref().unwrap_or(&package.task_retry);
+            let state = retry.run(&format!("task '{}'", task.name), || {
+                task.run(config, secrets, &template_context)
+            })?;
+            writeln!(output, "   + task '{}' ({})", task.name, state)?;
         }
 
         Ok(())
@@ -147,24 +259,26 @@ mod tests {
             "apply",
             "--config",
             get_test_data().to_str().unwrap(),
+            "--state-dir",
+            temp.path().join("state").to_str().unwrap(),
         ]);
 
         let output = crate::core::output::mock();
 
         let temp_path = temp.path().to_owned();
-        crate::core::file::File::apply.mock_safe(move |f, target, config, secrets| {
+        crate::core::file::File::apply.mock_safe(move |f, target, permissions, context, partials, strict| {
             let target = Box::leak(Box::new(temp_path.join(target.strip_prefix("/").unwrap())));
 
-            MockResult::Continue((f, target, config, secrets))
+            MockResult::Continue((f, target, permissions, context, partials, strict))
         });
 
-        crate::core::config::load_script_config.mock_safe(|interpreter, _file| {
+        crate::core::config::load_script_config.mock_safe(|interpreter, _file, _env| {
             assert_eq!(interpreter, "pwsh");
 
             MockResult::Return(Ok("TESTING=yes".to_string()))
         });
 
-        crate::core::script::run_script_task.mock_safe(|interpreter, _config, _file| {
+        crate::core::script::run_script_task.mock_safe(|interpreter, _config, _file, _options, _label| {
             assert_eq!(interpreter, "pwsh");
 
             MockResult::Return(Ok(()))
@@ -184,5 +298,121 @@ mod tests {
             output.to_string().contains(" + package 'test2'"),
             "the output should contain the second package"
         );
+
+        assert!(
+            output.to_string().contains("/etc/test.conf/test.conf' (changed)"),
+            "the output should report that the template was written"
+        );
+    }
+
+    #[test]
+    fn run_skips_unchanged_packages() {
+        let _guard = test_tracing();
+        let temp = tempfile::tempdir().unwrap();
+
+        let cmd = ApplyCommand {};
+
+        let config_dir = get_test_data();
+        let state_dir = temp.path().join("state");
+        let args = vec![
+            "apply",
+            "--config",
+            config_dir.to_str().unwrap(),
+            "--state-dir",
+            state_dir.to_str().unwrap(),
+        ];
+
+        let output = crate::core::output::mock();
+
+        let temp_path = temp.path().to_owned();
+        crate::core::file::File::apply.mock_safe(move |f, target, permissions, context, partials, strict| {
+            let target = Box::leak(Box::new(temp_path.join(target.strip_prefix("/").unwrap())));
+
+            MockResult::Continue((f, target, permissions, context, partials, strict))
+        });
+
+        crate::core::config::load_script_config.mock_safe(|_interpreter, _file, _env| {
+            MockResult::Return(Ok("TESTING=yes".to_string()))
+        });
+
+        crate::core::script::run_script_task.mock_safe(|_interpreter, _config, _file, _options, _label| {
+            MockResult::Return(Ok(()))
+        });
+
+        cmd.run(&cmd.app().get_matches_from(args.clone()))
+            .expect("the first run should succeed");
+        assert!(
+            output.to_string().contains(" + package 'test1'"),
+            "the first run should apply the package"
+        );
+
+        output.clear();
+        cmd.run(&cmd.app().get_matches_from(args.clone()))
+            .expect("the second run should succeed");
+        assert!(
+            output.to_string().contains(" = package 'test1' (unchanged)"),
+            "the second run should skip the unchanged package"
+        );
+
+        output.clear();
+        let mut forced = args.clone();
+        forced.push("--force");
+        cmd.run(&cmd.app().get_matches_from(forced))
+            .expect("the forced run should succeed");
+        assert!(
+            output.to_string().contains(" + package 'test1'"),
+            "the forced run should apply the package again"
+        );
+    }
+
+    #[test]
+    fn run_applies_packages_when_script_config_changes() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        let _guard = test_tracing();
+        let temp = tempfile::tempdir().unwrap();
+
+        let package_dir = temp.path().join("packages").join("dynamic");
+        std::fs::create_dir_all(package_dir.join("config")).unwrap();
+        std::fs::write(package_dir.join("package.yml"), "description: A package with script config.\n").unwrap();
+        std::fs::write(package_dir.join("config").join("metadata.sh"), "echo \"LOCATION=$(curl ...)\"").unwrap();
+
+        let cmd = ApplyCommand {};
+        let args = vec![
+            "apply".to_string(),
+            "--config".to_string(),
+            temp.path().to_string_lossy().to_string(),
+            "--state-dir".to_string(),
+            temp.path().join("state").to_string_lossy().to_string(),
+        ];
+
+        let output = crate::core::output::mock();
+
+        let location = Rc::new(Cell::new("westeurope"));
+        let script_location = location.clone();
+        crate::core::config::load_script_config.mock_safe(move |_interpreter, _file, _env| {
+            MockResult::Return(Ok(format!("LOCATION={}", script_location.get())))
+        });
+
+        cmd.run(&cmd.app().get_matches_from(args.clone()))
+            .expect("the first run should succeed");
+
+        output.clear();
+        cmd.run(&cmd.app().get_matches_from(args.clone()))
+            .expect("the second run should succeed");
+        assert!(
+            output.to_string().contains(" = package 'dynamic' (unchanged)"),
+            "the package should be skipped while its config script returns the same values"
+        );
+
+        output.clear();
+        location.set("northeurope");
+        cmd.run(&cmd.app().get_matches_from(args))
+            .expect("the third run should succeed");
+        assert!(
+            output.to_string().contains(" + package 'dynamic'"),
+            "the package should be applied again when its config script returns different values"
+        );
     }
 }
@@ -0,0 +1,401 @@
+use crate::core::config::{load_sources, merge_sources, ConfigSource};
+use crate::core::config_value::{to_env, ConfigValue};
+use crate::core::dotenv::quote;
+use crate::core::interpolate::interpolate;
+use crate::core::redact::MASK;
+use crate::core::retry::RetryConfig;
+use crate::errors;
+use clap::{Arg, ArgAction, value_parser};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use tracing::{info_span, instrument};
+use tracing_batteries::prelude::opentelemetry::trace::SpanKind;
+
+use super::*;
+
+#[derive(Debug)]
+pub struct ConfigCommand {}
+
+impl Command for ConfigCommand {
+    fn name(&self) -> String {
+        String::from("config")
+    }
+    fn app(&self) -> clap::Command {
+        clap::Command::new(self.name())
+            .version("1.0")
+            .about("inspects the config provided to your packages")
+            .long_about("Reads the config from your bootstrapping configuration and shows where its values come from, without applying any of your packages.")
+            .subcommand_required(true)
+            .arg_required_else_help(true)
+            .subcommand(clap::Command::new("explain")
+                .about("shows every config file which defines a key, and which value wins")
+                .arg(config_arg())
+                .arg(Arg::new("package")
+                        .short('p')
+                        .long("package")
+                        .value_name("NAME")
+                        .help("Only include this package. May be provided multiple times.")
+                        .action(ArgAction::Append)
+                        .value_parser(value_parser!(String)))
+                .arg(Arg::new("key")
+                        .value_name("KEY")
+                        .help("The name of the config value to explain.")
+                        .action(ArgAction::Set)
+                        .value_parser(value_parser!(String))
+                        .required(true)))
+            .subcommand(clap::Command::new("dump")
+                .about("shows the config and secrets provided to a package's files and tasks")
+                .long_about("Loads the global config and secrets, along with those of the selected package, and shows the merged values which would be provided to its files and tasks. Secrets are masked unless --show-secrets is provided.")
+                .arg(config_arg())
+                .arg(Arg::new("package")
+                        .short('p')
+                        .long("package")
+                        .value_name("NAME")
+                        .help("The package whose config should be shown. When omitted, only the global config is shown.")
+                        .action(ArgAction::Set)
+                        .value_parser(value_parser!(String)))
+                .arg(Arg::new("format")
+                        .short('f')
+                        .long("format")
+                        .value_name("FORMAT")
+                        .help("The format to show the config in.")
+                        .action(ArgAction::Set)
+                        .value_parser(["env", "json", "yaml"])
+                        .default_value("env"))
+                .arg(Arg::new("show-secrets")
+                        .long("show-secrets")
+                        .help("Show the values of secrets, rather than masking them.")
+                        .action(ArgAction::SetTrue))
+                .arg(Arg::new("secrets-key")
+                        .long("secrets-key")
+                        .env("BUCKLE_SECRETS_KEY")
+                        .value_name("FILE")
+                        .help("The key file used to decrypt encrypted files in your secrets directories.")
+                        .action(ArgAction::Set)
+                        .value_parser(value_parser!(PathBuf))))
+    }
+}
+
+fn config_arg() -> Arg {
+    Arg::new("config")
+        .short('c')
+        .long("config")
+        .env("BUCKLE_CONFIG")
+        .value_name("FOLDER")
+        .help("The path to your buckle configuration directory.")
+        .action(ArgAction::Set)
+        .value_parser(value_parser!(PathBuf))
+        .required(true)
+}
+
+impl CommandRunnable for ConfigCommand {
+    #[instrument(name = "command.config", fields(otel.kind = ?SpanKind::Client), skip(self, matches), err)]
+    fn run(&self, matches: &clap::ArgMatches) -> Result<i32, crate::errors::Error> {
+        match matches.subcommand() {
+            Some(("explain", matches)) => self.explain(matches),
+            Some(("dump", matches)) => self.dump(matches),
+            _ => Ok(2),
+        }
+    }
+}
+
+impl ConfigCommand {
+    #[instrument(name = "command.config.explain", skip(self, matches), fields(config.key), err)]
+    fn explain(&self, matches: &clap::ArgMatches) -> Result<i32, errors::Error> {
+        let config_dir: PathBuf =
+            matches
+                .get_one::<PathBuf>("config")
+                .cloned()
+                .ok_or_else(|| {
+                    errors::user(
+                        "No configuration directory provided.",
+                        "Provide the --config directory when running this command.",
+                    )
+                })?;
+
+        let key = matches.get_one::<String>("key").cloned().unwrap_or_default();
+        tracing::Span::current().record("config.key", key.as_str());
+
+        let include: Vec<String> = matches
+            .get_many::<String>("package")
+            .map(|v| v.cloned().collect())
+            .unwrap_or_default();
+
+        let mut output = crate::core::output::output();
+        let mut defined = false;
+
+        let sources = load_sources(&config_dir.join("config"), &RetryConfig::default(), &HashMap::new())?;
+        let config = interpolate(&merge_sources(&sources), &HashMap::new())?;
+
+        writeln!(output, " = config '{key}'")?;
+        defined |= explain_sources(&mut output, &config_dir, &sources, &config, &key)?;
+
+        let packages = crate::core::package::get_all_packages(&config_dir.join("packages"))?;
+        for package in packages {
+            if !include.is_empty() && !include.contains(&package.id) {
+                continue;
+            }
+
+            let _span = info_span!("package.explain", "package.id"=%package.id).entered();
+
+            let sources = package.get_config_sources(&config)?;
+            if !include.contains(&package.id) && !sources.iter().any(|s| s.values.contains_key(&key)) {
+                continue;
+            }
+
+            let mut package_config = config.clone();
+            package_config.extend(interpolate(&merge_sources(&sources), &config)?);
+
+            writeln!(output)?;
+            writeln!(output, " + package '{}'", package.id)?;
+            defined |= explain_sources(&mut output, &config_dir, &sources, &package_config, &key)?;
+        }
+
+        if !defined {
+            writeln!(output)?;
+            writeln!(output, " ! config '{key}' is not defined in any config file")?;
+            return Ok(1);
+        }
+
+        Ok(0)
+    }
+
+    #[instrument(name = "command.config.dump", skip(self, matches), fields(package.id), err)]
+    fn dump(&self, matches: &clap::ArgMatches) -> Result<i32, errors::Error> {
+        let config_dir: PathBuf =
+            matches
+                .get_one::<PathBuf>("config")
+                .cloned()
+                .ok_or_else(|| {
+                    errors::user(
+                        "No configuration directory provided.",
+                        "Provide the --config directory when running this command.",
+                    )
+                })?;
+
+        let secrets_key = matches.get_one::<PathBuf>("secrets-key").map(|p| p.as_path());
+        let show_secrets = matches.get_flag("show-secrets");
+        let format = matches.get_one::<String>("format").map(|f| f.as_str()).unwrap_or("env");
+
+        // The config and secrets are layered in exactly the same way as they are when a package is applied.
+        let mut config = crate::core::config::load_all_config(&config_dir.join("config"), &RetryConfig::default(), &HashMap::new())?;
+        let mut secrets = crate::core::config::load_all_secrets(&config_dir.join("secrets"), &RetryConfig::default(), secrets_key, &config)?;
+
+        if let Some(id) = matches.get_one::<String>("package") {
+            tracing::Span::current().record("package.id", id.as_str());
+
+            let packages = crate::core::package::get_all_packages(&config_dir.join("packages"))?;
+            let package = packages.into_iter().find(|p| &p.id == id).ok_or_else(|| {
+                errors::user(
+                    format!("The package '{id}' could not be found."),
+                    "Make sure that you have provided the name of a package in your packages directory.",
+                )
+            })?;
+
+            let package_config = package.get_config(&config)?;
+            config.extend(package_config);
+
+            let mut context = config.clone();
+            context.extend(secrets.clone());
+            secrets.extend(package.get_secrets(secrets_key, &context)?);
+        }
+
+        let mut values: BTreeMap<String, ConfigValue> = config.into_iter().collect();
+        for (key, val) in secrets {
+            let val = if show_secrets { val } else { ConfigValue::from(MASK) };
+            values.insert(key, val);
+        }
+
+        let content = match format {
+            "json" => serde_json::to_string_pretty(&values).map(|json| json + "\n").map_err(|e| e.to_string()),
+            "yaml" => serde_yaml::to_string(&values).map_err(|e| e.to_string()),
+            // Scripts see structured values as flattened variables (like `USERS_0_NAME`), so they are printed the same way.
+            _ => Ok(to_env(&values.clone().into_iter().collect())
+                .into_iter()
+                .collect::<BTreeMap<_, _>>()
+                .into_iter()
+                .map(|(key, val)| format!("{}={}\n", key, quote(&val)))
+                .collect()),
+        }
+        .map_err(|e| errors::system_with_internal(
+            format!("Failed to format your config as {format}."),
+            "Read the internal error message and take the appropriate steps to resolve the issue.",
+            errors::detailed_message(e),
+        ))?;
+
+        let mut output = if show_secrets {
+            crate::core::output::unredacted_output()
+        } else {
+            crate::core::output::output()
+        };
+
+        write!(output, "{content}")?;
+
+        Ok(0)
+    }
+}
+
+/// Lists each of the config files which defined `key`, marking those whose value was overridden
+/// by a later file, followed by the value it ended up with.
+fn explain_sources(
+    output: &mut dyn Write,
+    config_dir: &Path,
+    sources: &[ConfigSource],
+    config: &HashMap<String, ConfigValue>,
+    key: &str,
+) -> Result<bool, errors::Error> {
+    let definitions: Vec<&ConfigSource> = sources.iter().filter(|s| s.values.contains_key(key)).collect();
+
+    for (i, source) in definitions.iter().enumerate() {
+        let file = source.file.strip_prefix(config_dir).unwrap_or(&source.file);
+        let value = &source.values[key];
+
+        if i + 1 < definitions.len() {
+            writeln!(output, "   - {}: {key}={value} (overridden)", file.display())?;
+        } else {
+            writeln!(output, "   + {}: {key}={value}", file.display())?;
+        }
+    }
+
+    match config.get(key) {
+        Some(value) => writeln!(output, "   = {key}={value}")?,
+        None => writeln!(output, "   = {key} is not defined")?,
+    }
+
+    Ok(!definitions.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::{get_test_data, test_tracing};
+
+    #[test]
+    fn explain() {
+        let _guard = test_tracing();
+        let temp = tempfile::tempdir().unwrap();
+
+        std::fs::create_dir_all(temp.path().join("config")).unwrap();
+        std::fs::write(temp.path().join("config").join("10-site.env"), "DATA_DIR=/srv/${APP_NAME}").unwrap();
+        std::fs::write(temp.path().join("config").join("2-base.env"), "APP_NAME=buckle\nDATA_DIR=/srv").unwrap();
+
+        let package_dir = temp.path().join("packages").join("web");
+        std::fs::create_dir_all(package_dir.join("config")).unwrap();
+        std::fs::write(package_dir.join("package.yml"), "description: A web server.\n").unwrap();
+        std::fs::write(package_dir.join("config").join("app.env"), "APP_NAME=web").unwrap();
+
+        let cmd = ConfigCommand {};
+        let args = cmd.app().get_matches_from(vec![
+            "config",
+            "explain",
+            "--config",
+            temp.path().to_str().unwrap(),
+            "DATA_DIR",
+        ]);
+
+        let output = crate::core::output::mock();
+
+        let status = cmd.run(&args).expect("the config should be explained");
+        assert_eq!(status, 0);
+
+        let output = output.to_string();
+        let base = Path::new("config").join("2-base.env");
+        let site = Path::new("config").join("10-site.env");
+        assert!(output.contains(&format!("   - {}: DATA_DIR=/srv (overridden)", base.display())), "{}", output);
+        assert!(output.contains(&format!("   + {}: DATA_DIR=/srv/${{APP_NAME}}", site.display())), "{}", output);
+        assert!(output.contains("   = DATA_DIR=/srv/buckle"), "{}", output);
+        assert!(!output.contains("package 'web'"), "packages which do not define the key should be omitted");
+    }
+
+    #[test]
+    fn explain_undefined() {
+        let _guard = test_tracing();
+
+        let cmd = ConfigCommand {};
+        let args = cmd.app().get_matches_from(vec![
+            "config",
+            "explain",
+            "--config",
+            get_test_data().to_str().unwrap(),
+            "MISSING",
+        ]);
+
+        let output = crate::core::output::mock();
+
+        let status = cmd.run(&args).expect("the config should be explained");
+        assert_eq!(status, 1);
+        assert!(output.to_string().contains(" ! config 'MISSING' is not defined in any config file"));
+    }
+
+    #[test]
+    fn dump() {
+        let _guard = test_tracing();
+
+        let cmd = ConfigCommand {};
+        let args = cmd.app().get_matches_from(vec![
+            "config",
+            "dump",
+            "--config",
+            get_test_data().to_str().unwrap(),
+            "--package",
+            "test1",
+        ]);
+
+        let output = crate::core::output::mock();
+
+        let status = cmd.run(&args).expect("the config should be dumped");
+        assert_eq!(status, 0);
+
+        let output = output.to_string();
+        assert!(output.contains("TESTING=true\n"), "{}", output);
+        assert!(output.contains("enabled=true\n"), "{}", output);
+        assert!(output.contains("MAGIC=******\n"), "secrets should be masked: {}", output);
+    }
+
+    #[test]
+    fn dump_structured() {
+        let _guard = test_tracing();
+        let temp = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(temp.path().join("config")).unwrap();
+        std::fs::write(temp.path().join("config").join("users.yml"), "users:\n  - name: alice\n    admin: true\n").unwrap();
+
+        let cmd = ConfigCommand {};
+        let args = cmd.app().get_matches_from(vec!["config", "dump", "--config", temp.path().to_str().unwrap()]);
+
+        let output = crate::core::output::mock();
+
+        let status = cmd.run(&args).expect("the config should be dumped");
+        assert_eq!(status, 0);
+
+        let output = output.to_string();
+        assert!(output.contains("USERS_0_NAME=alice\n"), "{}", output);
+        assert!(output.contains("USERS_0_ADMIN=true\n"), "{}", output);
+    }
+
+    #[test]
+    fn dump_secrets() {
+        let _guard = test_tracing();
+
+        let cmd = ConfigCommand {};
+        let args = cmd.app().get_matches_from(vec![
+            "config",
+            "dump",
+            "--config",
+            get_test_data().to_str().unwrap(),
+            "--package",
+            "test2",
+            "--format",
+            "json",
+            "--show-secrets",
+        ]);
+
+        let output = crate::core::output::mock();
+
+        cmd.run(&args).expect("the config should be dumped");
+
+        let values: HashMap<String, ConfigValue> =
+            serde_json::from_str(&output.to_string()).expect("the output should be valid JSON");
+        assert_eq!(values.get("MAGIC"), Some(&ConfigValue::from("flash")));
+        assert_eq!(values.get("TESTING"), Some(&ConfigValue::from("true")));
+    }
+}
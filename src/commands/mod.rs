@@ -4,7 +4,9 @@ use std::sync::Arc;
 use std::{io::Write, vec::Vec};
 
 mod apply;
+mod config;
 mod plan;
+mod validate;
 
 pub trait Command: Send + Sync {
     fn name(&self) -> String;
@@ -18,6 +20,8 @@ pub trait CommandRunnable: Command {
 pub fn commands() -> Vec<Arc<dyn CommandRunnable>> {
     vec![
         Arc::new(apply::ApplyCommand {}),
+        Arc::new(config::ConfigCommand {}),
         Arc::new(plan::PlanCommand {}),
+        Arc::new(validate::ValidateCommand {}),
     ]
 }
@@ -1,5 +1,9 @@
+use crate::core::diff::{mask_secrets, unified_diff};
+use crate::core::file::FileChange;
+use crate::core::retry::RetryConfig;
 use crate::errors;
 use clap::{Arg, ArgAction, value_parser};
+use std::collections::HashMap;
 use std::path::PathBuf;
 use tracing::{info_span, instrument};
 use tracing_batteries::prelude::opentelemetry::trace::SpanKind;
@@ -17,7 +21,7 @@ impl Command for PlanCommand {
         clap::Command::new(self.name())
             .version("1.0")
             .about("shows the planned strategy for bootstrapping the local machine")
-            .long_about("Reads the bootstrapping configuration and shows how it would be executed if run against the local machine.")
+            .long_about("Reads the bootstrapping configuration and shows how it would be executed if run against the local machine, including a diff of every file which would be created or modified.")
             .arg(Arg::new("config")
                     .short('c')
                     .long("config")
@@ -27,6 +31,44 @@ impl Command for PlanCommand {
                     .action(ArgAction::Set)
                     .value_parser(value_parser!(PathBuf))
                     .required(true))
+            .arg(Arg::new("package")
+                    .short('p')
+                    .long("package")
+                    .value_name("NAME")
+                    .help("Only include this package (and the packages it needs). May be provided multiple times.")
+                    .action(ArgAction::Append)
+                    .value_parser(value_parser!(String)))
+            .arg(Arg::new("exclude")
+                    .short('x')
+                    .long("exclude")
+                    .value_name("NAME")
+                    .help("Exclude this package. May be provided multiple times.")
+                    .action(ArgAction::Append)
+                    .value_parser(value_parser!(String)))
+            .arg(Arg::new("secrets-key")
+                    .long("secrets-key")
+                    .env("BUCKLE_SECRETS_KEY")
+                    .value_name("FILE")
+                    .help("The key file used to decrypt encrypted files in your secrets directories.")
+                    .action(ArgAction::Set)
+                    .value_parser(value_parser!(PathBuf)))
+    }
+}
+
+#[derive(Debug, Default)]
+struct PlanSummary {
+    create: usize,
+    modify: usize,
+    unchanged: usize,
+}
+
+impl PlanSummary {
+    fn record(&mut self, change: FileChange) {
+        match change {
+            FileChange::Create => self.create += 1,
+            FileChange::Modify => self.modify += 1,
+            FileChange::Unchanged => self.unchanged += 1,
+        }
     }
 }
 
@@ -45,56 +87,115 @@ impl CommandRunnable for PlanCommand {
                 })?;
 
         let mut output = crate::core::output::output();
+        let secrets_key = matches.get_one::<PathBuf>("secrets-key").map(|p| p.as_path());
 
-        let config = crate::core::config::load_all_config(&config_dir.join("config"))?;
-        for (key, val) in config {
+        let config = crate::core::config::load_all_config(&config_dir.join("config"), &RetryConfig::default(), &HashMap::new())?;
+        for (key, val) in config.iter() {
             writeln!(output, " = config {key}={val}")?;
         }
 
-        let secrets = crate::core::config::load_all_config(&config_dir.join("secrets"))?;
-        for (key, _val) in secrets {
+        let secrets = crate::core::config::load_all_secrets(&config_dir.join("secrets"), &RetryConfig::default(), secrets_key, &config)?;
+        for key in secrets.keys() {
             writeln!(output, " = secret {key}=******")?;
         }
 
-        let packages = crate::core::package::get_all_packages(&config_dir.join("packages"))?;
+        let partials = crate::core::template::get_partials(&config_dir.join("templates"))?;
+        for partial in partials.iter() {
+            partial.validate()?;
+            writeln!(output, " = partial '{}'", partial.name)?;
+        }
+
+        let include: Vec<String> = matches
+            .get_many::<String>("package")
+            .map(|v| v.cloned().collect())
+            .unwrap_or_default();
+        let exclude: Vec<String> = matches
+            .get_many::<String>("exclude")
+            .map(|v| v.cloned().collect())
+            .unwrap_or_default();
+
+        let (packages, graph) = crate::core::package::get_package_graph(&config_dir.join("packages"))?;
+        let packages = crate::core::package::select_packages(packages, &graph, &include, &exclude)?;
+
+        let mut summary = PlanSummary::default();
 
         for package in packages {
             let _span = info_span!("package.plan", "package.id"=%package.id).entered();
             writeln!(output)?;
             writeln!(output, " + package '{}'", &package.id)?;
 
-            let config = package.get_config()?;
-            for (key, val) in config {
+            let mut config = config.clone();
+            for (key, val) in package.get_config(&config)? {
                 writeln!(output, "   = config {key}={val}")?;
+                config.insert(key, val);
             }
 
-            let secrets = package.get_secrets()?;
-            for (key, _val) in secrets {
+            let mut context = config.clone();
+            context.extend(secrets.clone());
+
+            let mut secrets = secrets.clone();
+            for (key, val) in package.get_secrets(secrets_key, &context)? {
                 writeln!(output, "   = secret {key}=******")?;
+                secrets.insert(key, val);
             }
 
+            // Package partials are registered after the shared ones, so they take precedence.
+            let mut partials = partials.clone();
+            for partial in package.get_partials()? {
+                partial.validate()?;
+                writeln!(output, "   = partial '{}'", partial.name)?;
+                partials.push(partial);
+            }
+
+            let template_context = crate::core::context::template_context(&package, &config, &secrets);
+
             let root_path = PathBuf::from("/");
             let files = package.get_files()?;
             for file in files {
-                let group = package
+                let file = file.resolve_path(&template_context)?;
+                let target_path = package
                     .files
                     .get(&file.group)
-                    .map(|f| f.as_path())
+                    .map(|g| g.path.as_path())
                     .unwrap_or(&root_path);
+                let output_path = target_path.join(&file.relative_path);
+
+                let plan = file.plan(target_path, &template_context, &partials, package.strict_templates.unwrap_or(true))?;
+                summary.record(plan.change);
+
                 writeln!(
                     output,
-                    "   + {} '{}'",
+                    "   + {} '{}' ({})",
                     if file.is_template { "template" } else { "file" },
-                    group.join(file.relative_path).display()
+                    output_path.display(),
+                    plan.change
                 )?;
+
+                if plan.change != FileChange::Unchanged {
+                    let diff = unified_diff(&output_path, plan.existing.as_deref(), &plan.desired);
+                    for line in mask_secrets(&diff, &secrets).lines() {
+                        writeln!(output, "     {line}")?;
+                    }
+                }
             }
 
             let tasks = package.get_tasks()?;
             for task in tasks {
-                writeln!(output, "   + task '{}'", &task.name)?;
+                if task.should_run(&template_context)? {
+                    writeln!(output, "   + task '{}'", task.name)?;
+                } else {
+                    writeln!(output, "   = task '{}' (skipped)", task.name)?;
+                }
             }
         }
 
+        writeln!(output)?;
+        writeln!(
+            output,
+            " = files: {} to create, {} to modify, {} unchanged",
+            summary.create, summary.modify, summary.unchanged
+        )?;
+
         Ok(0)
     }
 }
@@ -108,6 +209,7 @@ mod tests {
     #[test]
     fn run() {
         let _guard = test_tracing();
+        let temp = tempfile::tempdir().unwrap();
 
         let cmd = PlanCommand {};
         let args =
@@ -116,10 +218,17 @@ mod tests {
 
         let output = crate::core::output::mock();
 
-        crate::core::file::File::apply.mock_safe(|_f, _target, _config, _secrets| {
+        crate::core::file::File::apply.mock_safe(|_f, _target, _permissions, _context, _partials, _strict| {
             panic!("The file should not have been written during the planning phase.");
         });
 
+        let temp_path = temp.path().to_owned();
+        crate::core::file::File::plan.mock_safe(move |f, target, context, partials, strict| {
+            let target = Box::leak(Box::new(temp_path.join(target.strip_prefix("/").unwrap())));
+
+            MockResult::Continue((f, target, context, partials, strict))
+        });
+
         match cmd.run(&args) {
             Ok(_) => {}
             Err(err) => panic!("{}", err.message()),
@@ -134,5 +243,20 @@ mod tests {
             output.to_string().contains(" + package 'test2'"),
             "the output should contain the second package"
         );
+
+        assert!(
+            output.to_string().contains(" = partial 'header'"),
+            "the output should contain the shared partials"
+        );
+
+        assert!(
+            output.to_string().contains("     +enabled = true"),
+            "the output should contain the diff of the rendered template"
+        );
+
+        assert!(
+            output.to_string().contains(" = files: "),
+            "the output should contain a summary of the planned file changes"
+        );
     }
 }
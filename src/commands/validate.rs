@@ -0,0 +1,395 @@
+use crate::core::dependencies::{DependencyGraph, DependencyProblem};
+use crate::core::config_value::ConfigValue;
+use crate::core::package::Package;
+use crate::core::template::Partial;
+use crate::errors;
+use clap::{Arg, ArgAction, value_parser};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tracing::{info_span, instrument};
+use tracing_batteries::prelude::opentelemetry::trace::SpanKind;
+
+use super::*;
+
+#[derive(Debug)]
+pub struct ValidateCommand {}
+
+impl Command for ValidateCommand {
+    fn name(&self) -> String {
+        String::from("validate")
+    }
+    fn app(&self) -> clap::Command {
+        clap::Command::new(self.name())
+            .version("1.0")
+            .about("checks a bootstrapping configuration for problems without applying it")
+            .long_about("Reads the bootstrapping configuration and checks it for problems, such as missing dependencies, unmapped file groups, invalid templates and missing partials, without running any scripts on the local machine.")
+            .arg(Arg::new("config")
+                    .short('c')
+                    .long("config")
+                    .env("BUCKLE_CONFIG")
+                    .value_name("FOLDER")
+                    .help("The path to your buckle configuration directory.")
+                    .action(ArgAction::Set)
+                    .value_parser(value_parser!(PathBuf))
+                    .required(true))
+    }
+}
+
+#[derive(Debug)]
+struct Problem {
+    location: PathBuf,
+    message: String,
+}
+
+impl CommandRunnable for ValidateCommand {
+    #[instrument(name = "command.validate", fields(otel.kind = ?SpanKind::Client, problems), skip(self, matches), err)]
+    fn run(&self, matches: &clap::ArgMatches) -> Result<i32, crate::errors::Error> {
+        let config_dir: PathBuf =
+            matches
+                .get_one::<PathBuf>("config")
+                .cloned()
+                .ok_or_else(|| {
+                    errors::user(
+                        "No configuration directory provided.",
+                        "Provide the --config directory when running this command.",
+                    )
+                })?;
+
+        let mut output = crate::core::output::output();
+        let mut problems = Vec::new();
+
+        validate_config_dir(&config_dir.join("config"), &mut problems)?;
+        validate_config_dir(&config_dir.join("secrets"), &mut problems)?;
+
+        let global_config = load_static_context(Some(HashMap::new()), &[config_dir.join("config")], &mut problems);
+        let global_secrets = load_static_context(Some(HashMap::new()), &[config_dir.join("secrets")], &mut problems);
+
+        let partials = validate_partials(&config_dir.join("templates"), &[], &mut problems);
+
+        let mut packages = Vec::new();
+        for path in crate::core::package::get_package_paths(&config_dir.join("packages"))? {
+            match Package::load(&path) {
+                Ok(package) => packages.push(package),
+                Err(err) => problems.push(Problem {
+                    location: path.join("package.yml"),
+                    message: describe(&err),
+                }),
+            }
+        }
+
+        validate_dependencies(&packages, &mut problems);
+
+        for package in packages.iter() {
+            let _span = info_span!("package.validate", "package.id"=%package.id).entered();
+
+            validate_config_dir(&package.path().join("config"), &mut problems)?;
+            validate_config_dir(&package.path().join("secrets"), &mut problems)?;
+            let partials = validate_partials(&package.path().join("partials"), &partials, &mut problems);
+            validate_files(package, global_config.as_ref(), global_secrets.as_ref(), &partials, &mut problems)?;
+            validate_tasks(package, &mut problems)?;
+        }
+
+        tracing::Span::current().record("problems", problems.len());
+
+        if problems.is_empty() {
+            writeln!(output, " = configuration is valid ({} packages)", packages.len())?;
+            return Ok(0);
+        }
+
+        for (i, problem) in problems.iter().enumerate() {
+            let location = problem
+                .location
+                .strip_prefix(&config_dir)
+                .unwrap_or(&problem.location);
+            writeln!(output, " {}. {}: {}", i + 1, location.display(), problem.message)?;
+        }
+
+        writeln!(output)?;
+        writeln!(output, " ! found {} problems in the configuration", problems.len())?;
+
+        Ok(1)
+    }
+}
+
+fn validate_config_dir(dir: &Path, problems: &mut Vec<Problem>) -> Result<(), errors::Error> {
+    for file in crate::core::config::get_config_files(dir)? {
+        if crate::core::config::is_supported_file(&file) {
+            continue;
+        }
+
+        match file.extension().and_then(|e| e.to_str()) {
+            Some(extension) => problems.push(Problem {
+                location: file.clone(),
+                message: format!("The '{extension}' extension is not supported for config files."),
+            }),
+            None => problems.push(Problem {
+                location: file.clone(),
+                message: "Config files must have a file extension which tells buckle how to load them.".to_string(),
+            }),
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_dependencies(packages: &[Package], problems: &mut Vec<Problem>) {
+    let mut graph = DependencyGraph::new();
+    for package in packages {
+        graph.add(&package.id, &package.needs);
+    }
+
+    if let Err(dependency_problems) = graph.resolve() {
+        for problem in dependency_problems {
+            let location = match &problem {
+                DependencyProblem::Missing { package, .. } => packages
+                    .iter()
+                    .find(|p| &p.id == package)
+                    .map(|p| p.path().join("package.yml"))
+                    .unwrap_or_else(|| PathBuf::from("packages")),
+                DependencyProblem::Cycle(_) => PathBuf::from("packages"),
+            };
+
+            problems.push(Problem {
+                location,
+                message: problem.to_string(),
+            });
+        }
+    }
+}
+
+/// Loads the values from `dirs` (on top of `base`) which can be determined without running any
+/// scripts, so that the values referenced by templates can be checked. Returns `None`
+/// when some of them can only be determined by running a config script or decrypting a file.
+fn load_static_context(
+    base: Option<HashMap<String, ConfigValue>>,
+    dirs: &[PathBuf],
+    problems: &mut Vec<Problem>,
+) -> Option<HashMap<String, ConfigValue>> {
+    let mut context = base;
+
+    for dir in dirs {
+        match crate::core::config::load_static_config(dir) {
+            Ok(Some(values)) => {
+                if let Some(context) = context.as_mut() {
+                    context.extend(values);
+                }
+            }
+            Ok(None) => context = None,
+            Err(err) => {
+                problems.push(Problem {
+                    location: dir.clone(),
+                    message: describe(&err),
+                });
+                context = None;
+            }
+        }
+    }
+
+    context
+}
+
+/// Checks that each of the partials in `dir` can be parsed, returning them along with the
+/// `inherited` partials which templates in this scope may also include.
+fn validate_partials(dir: &Path, inherited: &[Partial], problems: &mut Vec<Problem>) -> Vec<Partial> {
+    let mut partials = inherited.to_vec();
+
+    match crate::core::template::get_partials(dir) {
+        Ok(loaded) => {
+            for partial in loaded {
+                if let Err(err) = partial.validate() {
+                    problems.push(Problem {
+                        location: partial.source_path.clone(),
+                        message: describe(&err),
+                    });
+                }
+
+                partials.push(partial);
+            }
+        }
+        Err(err) => problems.push(Problem {
+            location: dir.to_owned(),
+            message: describe(&err),
+        }),
+    }
+
+    partials
+}
+
+fn validate_files(
+    package: &Package,
+    global_config: Option<&HashMap<String, ConfigValue>>,
+    global_secrets: Option<&HashMap<String, ConfigValue>>,
+    partials: &[Partial],
+    problems: &mut Vec<Problem>,
+) -> Result<(), errors::Error> {
+    let files_dir = package.path().join("files");
+    let groups = crate::core::file::get_file_groups(&files_dir)?;
+
+    for group in groups.iter() {
+        if !package.files.contains_key(group) {
+            problems.push(Problem {
+                location: files_dir.join(group),
+                message: format!("The file group '{group}' does not have a target path in package.yml."),
+            });
+        }
+    }
+
+    for group in package.files.keys() {
+        if !groups.contains(group) {
+            problems.push(Problem {
+                location: package.path().join("package.yml"),
+                message: format!("The file group '{group}' does not have a matching files/{group} directory."),
+            });
+        }
+    }
+
+    // Templates are checked for references to undefined values unless the package opts out of strict templates.
+    let config = load_static_context(global_config.cloned(), &[package.path().join("config")], problems);
+    let secrets = load_static_context(global_secrets.cloned(), &[package.path().join("secrets")], problems);
+    let context = config
+        .zip(secrets)
+        .filter(|_| package.strict_templates.unwrap_or(true))
+        .map(|(config, secrets)| crate::core::context::template_context(package, &config, &secrets));
+
+    for file in package.get_files()? {
+        if let Err(err) = file.validate(context.as_ref(), partials) {
+            problems.push(Problem {
+                location: file.source_path.clone(),
+                message: describe(&err),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_tasks(package: &Package, problems: &mut Vec<Problem>) -> Result<(), errors::Error> {
+    let tasks = match package.get_tasks() {
+        Ok(tasks) => tasks,
+        Err(err) => {
+            problems.push(Problem {
+                location: package.path().join("scripts"),
+                message: describe(&err),
+            });
+            return Ok(());
+        }
+    };
+
+    for name in package.tasks.keys() {
+        if !tasks.iter().any(|t| &t.name == name) {
+            problems.push(Problem {
+                location: package.path().join("package.yml"),
+                message: format!("The task '{name}' does not have a matching scripts/{name} file."),
+            });
+        }
+    }
+
+    for task in tasks {
+        match task.path.extension().and_then(|e| e.to_str()) {
+            Some(extension) if crate::core::script::interpreter_for(extension).is_some() => {}
+            _ if crate::core::task_config::is_metadata(&task.path) => problems.push(Problem {
+                location: task.path.clone(),
+                message: format!(
+                    "The task metadata file '{}' does not have a matching scripts/{} file.",
+                    task.name,
+                    task.path.file_stem().map(|s| s.to_string_lossy()).unwrap_or_default()
+                ),
+            }),
+            Some(extension) => problems.push(Problem {
+                location: task.path.clone(),
+                message: format!("The '{extension}' extension is not supported for task files."),
+            }),
+            None => problems.push(Problem {
+                location: task.path.clone(),
+                message: "Task files must have a file extension which tells buckle how to run them.".to_string(),
+            }),
+        }
+    }
+
+    Ok(())
+}
+
+fn describe(err: &errors::Error) -> String {
+    let mut description = err.description();
+
+    let mut source = std::error::Error::source(err);
+    while let Some(err) = source {
+        description.push_str(&format!(" ({err})"));
+        source = err.source();
+    }
+
+    description
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::{get_test_data, test_tracing};
+
+    #[test]
+    fn run() {
+        let _guard = test_tracing();
+
+        let cmd = ValidateCommand {};
+        let args =
+            cmd.app()
+                .get_matches_from(vec!["validate", "--config", get_test_data().to_str().unwrap()]);
+
+        let output = crate::core::output::mock();
+
+        match cmd.run(&args) {
+            Ok(status) => assert_eq!(status, 0, "the test configuration should be valid: {}", output.to_string()),
+            Err(err) => panic!("{}", err.message()),
+        }
+
+        assert!(
+            output.to_string().contains(" = configuration is valid"),
+            "the output should report that the configuration is valid"
+        );
+    }
+
+    #[test]
+    fn run_with_problems() {
+        let _guard = test_tracing();
+        let temp = tempfile::tempdir().unwrap();
+
+        let package_dir = temp.path().join("packages").join("broken");
+        std::fs::create_dir_all(package_dir.join("files").join("unmapped")).unwrap();
+        std::fs::create_dir_all(package_dir.join("scripts")).unwrap();
+        std::fs::write(
+            package_dir.join("package.yml"),
+            "description: A broken package.\nneeds:\n  - missing\nfiles:\n  absent: /etc/absent\n",
+        )
+        .unwrap();
+        std::fs::write(package_dir.join("files").join("unmapped").join("bad.conf.tpl"), "enabled = {{ .enabled").unwrap();
+        std::fs::write(package_dir.join("files").join("unmapped").join("typo.conf.tpl"), "\nport = {{ .PROT }}").unwrap();
+        std::fs::write(package_dir.join("files").join("unmapped").join("header.conf.tpl"), "{{ template \"header\" . }}").unwrap();
+        std::fs::write(package_dir.join("files").join("unmapped").join("{{ .SITE }}.conf"), "").unwrap();
+        std::fs::create_dir_all(package_dir.join("partials")).unwrap();
+        std::fs::write(package_dir.join("partials").join("footer.tpl"), "{{ .unterminated").unwrap();
+        std::fs::write(package_dir.join("scripts").join("install.py"), "print('hello')").unwrap();
+        std::fs::write(package_dir.join("scripts").join("removed.sh.yml"), "timeout: 10").unwrap();
+
+        let cmd = ValidateCommand {};
+        let args =
+            cmd.app()
+                .get_matches_from(vec!["validate", "--config", temp.path().to_str().unwrap()]);
+
+        let output = crate::core::output::mock();
+
+        let status = cmd.run(&args).expect("validation should complete");
+        assert_eq!(status, 1, "validation should fail");
+
+        let output = output.to_string();
+        assert!(output.contains("'broken' needs 'missing'"), "the missing dependency should be reported");
+        assert!(output.contains("'unmapped' does not have a target path"), "the unmapped file group should be reported");
+        assert!(output.contains("'absent' does not have a matching"), "the missing file group directory should be reported");
+        assert!(output.contains("'py' extension is not supported"), "the unsupported script should be reported");
+        assert!(output.contains("'removed.sh.yml' does not have a matching scripts/removed.sh"), "the orphaned task metadata should be reported");
+        assert!(output.contains("bad.conf.tpl"), "the invalid template should be reported");
+        assert!(output.contains("references '.PROT' on line 2"), "the undefined template value should be reported");
+        assert!(output.contains("includes the partial 'header' on line 1"), "the missing partial should be reported");
+        assert!(output.contains("Could not parse the partial"), "the invalid partial should be reported");
+        assert!(output.contains("references '.SITE', which is not defined"), "the undefined value in a file's path should be reported");
+    }
+}
@@ -1,22 +1,170 @@
-use std::{collections::HashMap, path::Path};
+use std::{collections::HashMap, path::{Path, PathBuf}};
 
 use std::fs::read_to_string;
 use std::process;
 use tracing::field::display;
-use tracing::{instrument, Span};
+use tracing::{debug, instrument, Span};
 
 use crate::errors;
 
+use super::config_value::{to_env, ConfigValue};
+use super::process::{Completion, Stream, TERMINATION_GRACE_PERIOD};
+use super::redact::redact;
+use super::retry::RetryConfig;
+use super::script::{interpreter_for, print_line};
+
 #[cfg(test)]
 use mocktopus::macros::*;
 
-#[instrument(level = "debug", name = "config.load_all", err)]
-pub fn load_all_config(dir: &Path) -> Result<HashMap<String, String>, errors::Error> {
+/// Loads every config file in `dir`, resolving the `${NAME}` references in their values against
+/// one another and then against `base` (the config which these values will be layered on top of).
+#[instrument(level = "debug", name = "config.load_all", skip(base), err)]
+pub fn load_all_config(
+    dir: &Path,
+    retry: &RetryConfig,
+    base: &HashMap<String, ConfigValue>,
+) -> Result<HashMap<String, ConfigValue>, errors::Error> {
+    let sources = load_sources(dir, retry, base)?;
+    super::interpolate::interpolate(&merge_sources(&sources), base)
+}
+
+/// Loads secrets in the same way as [load_all_config], decrypting encrypted files with the
+/// given key and registering their values so that they are masked wherever Buckle would
+/// otherwise show them. Secrets are used exactly as they are written, without interpolation.
+#[instrument(level = "debug", name = "config.load_all_secrets", skip(base), err)]
+pub fn load_all_secrets(
+    dir: &Path,
+    retry: &RetryConfig,
+    key: Option<&Path>,
+    base: &HashMap<String, ConfigValue>,
+) -> Result<HashMap<String, ConfigValue>, errors::Error> {
+    let sources = load_dir_sources(dir, retry, key, base, |values, _base| Ok(values.clone()))?;
+    let secrets = merge_sources(&sources);
+    super::redact::register(secrets.iter().flat_map(|(key, v)| v.scalars().into_iter().map(move |s| (key, s))));
+    Ok(secrets)
+}
+
+/// Merges the values from each config file, with values from later files overriding those
+/// from earlier ones.
+pub fn merge_sources(sources: &[ConfigSource]) -> HashMap<String, ConfigValue> {
+    let mut output = HashMap::new();
+    let mut origins: HashMap<&str, &Path> = HashMap::new();
+
+    for source in sources.iter() {
+        for (name, val) in source.values.iter() {
+            if let Some(previous) = origins.insert(name, &source.file) {
+                debug!(
+                    config.key = %name,
+                    config.file = %source.file.display(),
+                    config.overridden_file = %previous.display(),
+                    "A config value was overridden by a later config file."
+                );
+            }
+
+            output.insert(name.clone(), val.clone());
+        }
+    }
+
+    output
+}
+
+/// A config file, along with the values which were loaded from it.
+#[derive(Debug, Clone)]
+pub struct ConfigSource {
+    pub file: PathBuf,
+    pub values: HashMap<String, ConfigValue>,
+}
+
+/// Loads each of the config files in `dir`, in the order of precedence in which their values
+/// are applied (so values from later files override those from earlier ones).
+pub fn load_sources(
+    dir: &Path,
+    retry: &RetryConfig,
+    base: &HashMap<String, ConfigValue>,
+) -> Result<Vec<ConfigSource>, errors::Error> {
+    load_dir_sources(dir, retry, None, base, super::interpolate::interpolate)
+}
+
+/// Determines the values which config scripts are provided with, from those loaded before them.
+type Resolver = fn(&HashMap<String, ConfigValue>, &HashMap<String, ConfigValue>) -> Result<HashMap<String, ConfigValue>, errors::Error>;
+
+/// Loads each of the files in `dir` in order. Config scripts are run with `base` (and the values
+/// loaded from the files before them, as determined by `resolve`) provided as environment variables.
+fn load_dir_sources(
+    dir: &Path,
+    retry: &RetryConfig,
+    key: Option<&Path>,
+    base: &HashMap<String, ConfigValue>,
+    resolve: Resolver,
+) -> Result<Vec<ConfigSource>, errors::Error> {
+    let files = get_config_files(dir)?;
+
+    let mut sources = Vec::new();
+    let mut errs: Vec<errors::Error> = Vec::new();
+
+    for file in files.iter() {
+        let file = dunce::simplified(file);
+
+        let loaded = match Format::for_file(file) {
+            Some(_) => load_config(file, retry, key, base),
+            None => resolve(&merge_sources(&sources), base).and_then(|values| {
+                let mut context = base.clone();
+                context.extend(values);
+                load_config(file, retry, key, &context)
+            }),
+        };
+
+        match loaded {
+            Ok(values) => sources.push(ConfigSource {
+                file: file.to_path_buf(),
+                values,
+            }),
+            Err(err) => errs.push(err),
+        }
+    }
+
+    if errs.is_empty() {
+        return Ok(sources);
+    }
+
+    Err(errors::composite(
+        format!("{} of the config files in '{}' could not be loaded.", errs.len(), dir.display()),
+        "Review each of the problems listed below and correct the config files responsible for them.",
+        errs,
+    ))
+}
+
+/// Loads the config in `dir` without running any config scripts or decrypting any files. Returns
+/// `None` when the directory holds files whose values can only be determined by doing so.
+#[instrument(level = "debug", name = "config.load_static", err)]
+pub fn load_static_config(dir: &Path) -> Result<Option<HashMap<String, ConfigValue>>, errors::Error> {
+    let files = get_config_files(dir)?;
+    if files.iter().any(|f| Format::for_file(f).is_none() || Encryption::for_file(f).is_some()) {
+        return Ok(None);
+    }
+
+    let sources = files
+        .iter()
+        .map(|file| {
+            let file = dunce::simplified(file);
+            Ok(ConfigSource {
+                file: file.to_path_buf(),
+                values: load_config(file, &RetryConfig::default(), None, &HashMap::new())?,
+            })
+        })
+        .collect::<Result<Vec<_>, errors::Error>>()?;
+
+    Ok(Some(merge_sources(&sources)))
+}
+
+#[instrument(level = "debug", name = "config.get_files", err)]
+pub fn get_config_files(dir: &Path) -> Result<Vec<PathBuf>, errors::Error> {
     if !dir.exists() {
-        return Ok(HashMap::new());
+        return Ok(Vec::new());
     }
 
-    dir.read_dir()
+    let mut files: Vec<PathBuf> = dir
+        .read_dir()
         .map(|dirs| {
             dirs.filter_map(|dir| match dir {
                 Ok(d) => match d.file_type() {
@@ -25,37 +173,158 @@ pub fn load_all_config(dir: &Path) -> Result<HashMap<String, String>, errors::Er
                 },
                 _ => None,
             })
+            .collect()
         })
         .map_err(|err| {
             errors::user_with_internal(
             "Failed to read the list of configuration files.", 
             "Read the internal error message and take the appropriate steps to resolve the issue.", 
             err)
-        })
-        .and_then(|files| {
-            let mut output = HashMap::new();
-
-            let mut errs: Vec<errors::Error> = files
-                .map(|file| {
-                    load_config(dunce::simplified(&file)).map(|config| {
-                        for (key, val) in config {
-                            output.insert(key, val);
-                        }
-                    })
-                })
-                .filter(|r| r.is_err())
-                .map(|r| r.unwrap_err())
-                .collect();
-
-            match errs.pop() {
-                Some(err) => Err(err),
-                None => Ok(output),
-            }
-        })
+        })?;
+
+    // Files are loaded in a predictable order (regardless of the order the filesystem lists
+    // them in), comparing numbers by their value so that `2-base.env` comes before `10-site.env`.
+    files.sort_by_cached_key(|file| precedence_key(file));
+
+    Ok(files)
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum NameChunk {
+    Number(u128, usize),
+    Text(String),
+}
+
+fn precedence_key(file: &Path) -> Vec<NameChunk> {
+    let name = file.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+
+    let mut chunks = Vec::new();
+    let mut rest = name.as_ref();
+    while let Some(first) = rest.chars().next() {
+        let is_digit = first.is_ascii_digit();
+        let end = rest.find(|c: char| c.is_ascii_digit() != is_digit).unwrap_or(rest.len());
+        let (chunk, remainder) = rest.split_at(end);
+
+        chunks.push(match chunk.parse() {
+            Ok(number) if is_digit => NameChunk::Number(number, chunk.len()),
+            _ => NameChunk::Text(chunk.to_string()),
+        });
+
+        rest = remainder;
+    }
+
+    chunks
+}
+
+/// The formats in which config files may be written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Env,
+    Yaml,
+    Json,
+    Toml,
+}
+
+impl Format {
+    /// Determines the format of a config file's (decrypted) content from its file extension.
+    pub fn for_file(file: &Path) -> Option<Format> {
+        let name = file.file_name()?.to_str()?;
+        let name = name.strip_suffix(".age").unwrap_or(name);
+
+        match Path::new(name).extension()?.to_str()? {
+            "env" => Some(Format::Env),
+            "yml" | "yaml" => Some(Format::Yaml),
+            "json" => Some(Format::Json),
+            "toml" => Some(Format::Toml),
+            _ => None,
+        }
+    }
+
+    pub fn parse(&self, file: &Path, content: &str) -> Result<HashMap<String, ConfigValue>, errors::Error> {
+        if content.trim().is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let parsed = match self {
+            Format::Env => parse_env(content, false),
+            Format::Yaml => serde_yaml::from_str(content).map_err(|e| e.into()),
+            Format::Json => serde_json::from_str(content).map_err(|e| e.into()),
+            Format::Toml => toml::from_str(content).map_err(|e| e.into()),
+        };
+
+        parsed.map_err(|e: Box<dyn std::error::Error + Send + Sync>| errors::user_with_internal(
+            format!("The config file '{}' could not be parsed.", file.display()),
+            format!("Make sure that the file is valid {self} and that it contains a map of config keys to their values."),
+            e))
+    }
+}
+
+impl std::fmt::Display for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Format::Env => write!(f, "dotenv"),
+            Format::Yaml => write!(f, "YAML"),
+            Format::Json => write!(f, "JSON"),
+            Format::Toml => write!(f, "TOML"),
+        }
+    }
+}
+
+/// The ways in which a config file may be encrypted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encryption {
+    /// A file ending in `.age` (such as `keys.env.age`), encrypted with [age](https://age-encryption.org).
+    Age,
+    /// A file with a `.sops` suffix before its extension (such as `keys.sops.yaml`), encrypted
+    /// with [sops](https://github.com/getsops/sops).
+    Sops,
+}
+
+impl Encryption {
+    pub fn for_file(file: &Path) -> Option<Encryption> {
+        Format::for_file(file)?;
+
+        let name = file.file_name()?.to_str()?;
+        if name.ends_with(".age") {
+            Some(Encryption::Age)
+        } else if file.file_stem()?.to_str()?.ends_with(".sops") {
+            Some(Encryption::Sops)
+        } else {
+            None
+        }
+    }
+}
+
+/// Determines whether buckle knows how to load the given config file.
+pub fn is_supported_file(file: &Path) -> bool {
+    if Format::for_file(file).is_some() {
+        return true;
+    }
+
+    match file.extension().and_then(|e| e.to_str()) {
+        Some(extension) => interpreter_for(extension).is_some(),
+        None => false,
+    }
 }
 
-#[instrument(level = "info", name = "config.load", err)]
-pub fn load_config(file: &Path) -> Result<HashMap<String, String>, errors::Error> {
+/// Loads the values from a config file. Config scripts are provided with `context` as environment
+/// variables, so that they can build upon the config which was loaded before them.
+#[instrument(level = "info", name = "config.load", skip(key, context), err)]
+pub fn load_config(
+    file: &Path,
+    retry: &RetryConfig,
+    key: Option<&Path>,
+    context: &HashMap<String, ConfigValue>,
+) -> Result<HashMap<String, ConfigValue>, errors::Error> {
+    if let Some(format) = Format::for_file(file) {
+        let content = match Encryption::for_file(file) {
+            Some(encryption) => decrypt_config(encryption, file, key)?,
+            None => load_file_config(file)?,
+        };
+
+        return format.parse(file, &content);
+    }
+
     let extension = match file.extension() {
         Some(ext) => ext.to_str().ok_or_else(|| errors::user(
             &format!("Unable to parse the file extension used by the config file '{}'", file.display()),
@@ -66,28 +335,34 @@ pub fn load_config(file: &Path) -> Result<HashMap<String, String>, errors::Error
             "Use one of the supported file extensions to tell buckle how to read this config file."))?
     };
 
-    let content = match extension {
-        "env" => load_env_config(file)?,
-        "ps1" => load_script_config("pwsh", file)?,
-        "sh" => load_script_config("bash", file)?,
-        "bat" => load_script_config("cmd.exe", file)?,
-        "cmd" => load_script_config("cmd.exe", file)?,
-        _ => Err(errors::user(
+    let env = to_env(context);
+    let content = match interpreter_for(extension) {
+        Some(interpreter) => retry.run(
+            &format!("config script '{}'", file.display()),
+            || load_script_config(interpreter, file, &env),
+        )?,
+        None => Err(errors::user(
             &format!(
-                "The '{extension}' extension is not supported for config files."
+                "The '{extension}' extension used by the config file '{}' is not supported.",
+                file.display()
             ),
             "Try using a file extension that is supported by buckle.",
         ))?,
     };
 
-    Ok(parse_config(&content))
+    // Scripts may write other information to their stdout, so anything which isn't a
+    // KEY=value pair is ignored.
+    parse_env(&content, true).map_err(|e| errors::user_with_internal(
+        format!("The output of the config script '{}' could not be parsed.", file.display()),
+        "Make sure that the script writes its config to stdout as KEY=value pairs, quoting any values which span multiple lines.",
+        e))
 }
 
-#[instrument(level = "debug", name = "config.load.env", err)]
-fn load_env_config(file: &Path) -> Result<String, errors::Error> {
+#[instrument(level = "debug", name = "config.load.file", err)]
+fn load_file_config(file: &Path) -> Result<String, errors::Error> {
     read_to_string(file).map_err(|e| {
         errors::user_with_internal(
-            "Unable to read configuration file due to an OS-level error.",
+            format!("Unable to read the configuration file '{}' due to an OS-level error.", file.display()),
             "Read the internal error message and take the appropriate steps to resolve the issue.",
             e,
         )
@@ -96,49 +371,203 @@ fn load_env_config(file: &Path) -> Result<String, errors::Error> {
 
 #[allow(clippy::swap_ptr_to_ref)]
 #[cfg_attr(test, mockable)]
-#[instrument(
-    level = "debug",
-    name = "config.load.script",
-    fields(stdout, stderr),
-    err
-)]
-pub fn load_script_config(interpreter: &str, file: &Path) -> Result<String, errors::Error> {
-    process::Command::new(interpreter)
-        .arg(file)
-        .output()
-        .map_err(|err| errors::user_with_internal(
-            &format!("Failed to execute the command '{} {}'.", interpreter, file.display()), 
-            &format!("Make sure that '{interpreter}' is installed and present on your path and that you have permission to access it."),
-            err))
-        .and_then(|output| {
-            let stdout = String::from_utf8_lossy(&output.stdout);
-            let stderr = String::from_utf8_lossy(&output.stderr);
-
-            Span::current().record("stdout", &display(String::from_utf8_lossy(&output.stdout)));
-            Span::current().record("stderr", &display(String::from_utf8_lossy(&output.stderr)));
-            if output.status.success() {
-                Ok(stdout.to_string())
-            } else {
-                Err(errors::user_with_internal(
-                    "Failed to load configuration from script.",
-                    "Read the internal error message and take the appropriate steps to resolve the issue.",
-                    errors::detailed_message(format!(
-                        "---- STDOUT: ----\n{stdout}\n\n---- STDERR: ----\n{stderr}"))))
+#[instrument(level = "debug", name = "config.load.encrypted", skip(key), err)]
+pub fn decrypt_config(encryption: Encryption, file: &Path, key: Option<&Path>) -> Result<String, errors::Error> {
+    let mut command = match (encryption, key) {
+        (Encryption::Age, Some(key)) => {
+            let mut command = process::Command::new("age");
+            command.arg("--decrypt").arg("--identity").arg(key).arg(file);
+            command
+        }
+        (Encryption::Age, None) => Err(errors::user(
+            format!("Could not decrypt the secrets file '{}' because no secrets key was provided.", file.display()),
+            "Provide the path to your age identity file using --secrets-key (or the BUCKLE_SECRETS_KEY environment variable)."))?,
+        (Encryption::Sops, key) => {
+            let mut command = process::Command::new("sops");
+            command.arg("--decrypt").arg(file);
+            if let Some(key) = key {
+                command.env("SOPS_AGE_KEY_FILE", key);
             }
-        })
+            command
+        }
+    };
+
+    run_loader(&mut command, file)
 }
 
-fn parse_config(content: &str) -> HashMap<String, String> {
-    let mut output = HashMap::new();
+#[allow(clippy::swap_ptr_to_ref)]
+#[cfg_attr(test, mockable)]
+#[instrument(level = "debug", name = "config.load.script", skip(env), err)]
+pub fn load_script_config(interpreter: &str, file: &Path, env: &HashMap<String, String>) -> Result<String, errors::Error> {
+    run_loader(process::Command::new(interpreter).arg(file).envs(env), file)
+}
 
-    let pairs = content
-        .split_terminator('\n')
-        .map(|line| line.trim())
-        .filter_map(|line| line.split_once('='));
+/// Runs a command which writes the config from `file` to its stdout.
+#[instrument(level = "debug", name = "config.load.command", fields(stderr), skip(command), err)]
+fn run_loader(command: &mut process::Command, file: &Path) -> Result<String, errors::Error> {
+    let program = command.get_program().to_string_lossy().to_string();
+    let label = file
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
 
-    for (key, value) in pairs {
-        output.insert(key.to_owned(), value.to_owned());
+    // The script's stdout holds the config it has loaded (which may be secret), so only its
+    // stderr is shown to the operator, included in errors and recorded in telemetry.
+    let mut content = String::new();
+    let mut output = crate::core::output::output();
+    let execution = super::process::run(
+        command,
+        None,
+        TERMINATION_GRACE_PERIOD,
+        &mut |stream, line| match stream {
+            Stream::Stdout => {
+                content.push_str(line);
+                content.push('\n');
+            }
+            Stream::Stderr => print_line(&mut output, &label, stream, line),
+        },
+    )
+    .map_err(|err| errors::user_with_internal(
+        format!("Failed to execute the command '{} {}'.", program, file.display()), 
+        format!("Make sure that '{program}' is installed and present on your path and that you have permission to access it."),
+        err))?;
+
+    let stderr = execution.stderr;
+    Span::current().record("stderr", display(redact(&stderr.to_string())));
+
+    match execution.completion {
+        Completion::Exited(status) if status.success() => Ok(content),
+        Completion::Interrupted => Err(errors::user_with_internal(
+            format!("Loading the config file '{}' was stopped because buckle was interrupted.", file.display()),
+            "Run buckle again once you are ready to complete the remaining tasks.",
+            errors::detailed_message(format!("---- STDERR: ----\n{stderr}")))),
+        _ => Err(errors::user_with_internal(
+            format!("Failed to load configuration from '{}'.", file.display()),
+            "Read the internal error message and take the appropriate steps to resolve the issue.",
+            errors::detailed_message(format!("---- STDERR: ----\n{stderr}")))),
     }
+}
 
-    output
+fn parse_env(content: &str, skip_invalid: bool) -> Result<HashMap<String, ConfigValue>, Box<dyn std::error::Error + Send + Sync>> {
+    let pairs = super::dotenv::parse(content, skip_invalid)?;
+
+    Ok(pairs
+        .into_iter()
+        .map(|(key, value)| (key, ConfigValue::String(value)))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use mocktopus::mocking::{MockResult, Mockable};
+
+    use super::*;
+
+    #[test]
+    fn load_encrypted() {
+        let temp = tempfile::tempdir().unwrap();
+        let file = temp.path().join("keys.env.age");
+        std::fs::write(&file, "encrypted").unwrap();
+
+        decrypt_config.mock_safe(|encryption, _file, key| {
+            assert_eq!(encryption, Encryption::Age);
+            assert_eq!(key, Some(Path::new("/keys/buckle.txt")));
+
+            MockResult::Return(Ok("API_KEY=abc123".to_string()))
+        });
+
+        let secrets = load_all_secrets(temp.path(), &RetryConfig::default(), Some(Path::new("/keys/buckle.txt")), &HashMap::new())
+            .expect("the secrets should be decrypted");
+        assert_eq!(secrets.get("API_KEY").map(|v| v.to_string()), Some("abc123".to_string()));
+    }
+
+    #[test]
+    fn load_in_precedence_order() {
+        let temp = tempfile::tempdir().unwrap();
+        std::fs::write(temp.path().join("10-site.env"), "LEVEL=site").unwrap();
+        std::fs::write(temp.path().join("2-base.env"), "LEVEL=base\nBASE=true").unwrap();
+        std::fs::write(temp.path().join("local.env"), "LOCAL=true").unwrap();
+
+        let files: Vec<String> = get_config_files(temp.path())
+            .unwrap()
+            .iter()
+            .map(|f| f.file_name().unwrap().to_string_lossy().to_string())
+            .collect();
+        assert_eq!(files, vec!["2-base.env", "10-site.env", "local.env"]);
+
+        let config = load_all_config(temp.path(), &RetryConfig::default(), &HashMap::new())
+            .expect("the config should load");
+        assert_eq!(config.get("LEVEL").map(|v| v.to_string()), Some("site".to_string()));
+        assert_eq!(config.get("BASE").map(|v| v.to_string()), Some("true".to_string()));
+    }
+
+    #[test]
+    fn load_reports_every_error() {
+        let temp = tempfile::tempdir().unwrap();
+        std::fs::write(temp.path().join("a.yaml"), "- not\n- a map").unwrap();
+        std::fs::write(temp.path().join("b.env"), "valid=true").unwrap();
+        std::fs::write(temp.path().join("c.env"), "not a pair").unwrap();
+
+        let err = load_all_config(temp.path(), &RetryConfig::default(), &HashMap::new())
+            .expect_err("the config should not load");
+
+        assert!(err.description().starts_with("2 of the config files"), "{}", err.description());
+
+        let message = err.to_string();
+        assert!(message.contains(&format!("1. The config file '{}' could not be parsed.", temp.path().join("a.yaml").display())), "{}", message);
+        assert!(message.contains(&format!("2. The config file '{}' could not be parsed.", temp.path().join("c.env").display())), "{}", message);
+        assert!(message.contains("line 1: expected a KEY=value pair"), "{}", message);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn load_script_with_context() {
+        let temp = tempfile::tempdir().unwrap();
+        std::fs::write(temp.path().join("1-base.env"), "ENDPOINT=https://${LOCATION}.example.com").unwrap();
+        std::fs::write(temp.path().join("2-lookup.sh"), "echo \"URL=$ENDPOINT/$PACKAGE\"").unwrap();
+
+        let mut base = HashMap::new();
+        base.insert("LOCATION".to_string(), ConfigValue::from("westeurope"));
+        base.insert("PACKAGE".to_string(), ConfigValue::from("web"));
+
+        let config = load_all_config(temp.path(), &RetryConfig::default(), &base).expect("the config should load");
+        assert_eq!(
+            config.get("URL").map(|v| v.to_string()),
+            Some("https://westeurope.example.com/web".to_string())
+        );
+    }
+
+    #[test]
+    fn load_encrypted_without_key() {
+        let err = decrypt_config(Encryption::Age, Path::new("keys.env.age"), None)
+            .expect_err("an age file cannot be decrypted without a key");
+
+        assert!(err.description().contains("no secrets key was provided"));
+    }
+
+    #[test]
+    fn encryption_for_file() {
+        assert_eq!(Encryption::for_file(Path::new("keys.env.age")), Some(Encryption::Age));
+        assert_eq!(Encryption::for_file(Path::new("keys.yaml.age")), Some(Encryption::Age));
+        assert_eq!(Encryption::for_file(Path::new("keys.sops.env")), Some(Encryption::Sops));
+        assert_eq!(Encryption::for_file(Path::new("keys.sops.json")), Some(Encryption::Sops));
+        assert_eq!(Encryption::for_file(Path::new("keys.env")), None);
+
+        assert!(is_supported_file(Path::new("keys.env.age")));
+        assert!(!is_supported_file(Path::new("keys.txt.age")));
+    }
+
+    #[test]
+    fn load_structured() {
+        let temp = tempfile::tempdir().unwrap();
+        std::fs::write(temp.path().join("a.yml"), "users:\n  - name: alice\n").unwrap();
+        std::fs::write(temp.path().join("b.json"), r#"{"port": 8080}"#).unwrap();
+        std::fs::write(temp.path().join("c.toml"), "[db]\nhost = \"localhost\"\n").unwrap();
+
+        let config = load_all_config(temp.path(), &RetryConfig::default(), &HashMap::new()).expect("the config should load");
+
+        assert_eq!(config["users"].to_string(), r#"[{"name":"alice"}]"#);
+        assert_eq!(config["port"].to_string(), "8080");
+        assert_eq!(config["db"].to_string(), r#"{"host":"localhost"}"#);
+    }
 }
@@ -0,0 +1,173 @@
+use std::collections::{BTreeMap, HashMap};
+
+use gtmpl::Value;
+use serde::{Deserialize, Serialize};
+
+/// A config value, which may be a nested structure when it was loaded from a YAML, JSON or
+/// TOML config file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ConfigValue {
+    Null,
+    Bool(bool),
+    Number(serde_json::Number),
+    String(String),
+    List(Vec<ConfigValue>),
+    Map(BTreeMap<String, ConfigValue>),
+}
+
+impl ConfigValue {
+    /// Converts this value into the form used when rendering templates, preserving its structure.
+    pub fn to_template(&self) -> Value {
+        match self {
+            ConfigValue::Null => Value::Nil,
+            ConfigValue::Bool(b) => Value::Bool(*b),
+            ConfigValue::Number(n) => match (n.as_i64(), n.as_u64(), n.as_f64()) {
+                (Some(i), _, _) => Value::from(i),
+                (_, Some(u), _) => Value::from(u),
+                (_, _, Some(f)) => Value::from(f),
+                _ => Value::String(n.to_string()),
+            },
+            ConfigValue::String(s) => Value::String(s.clone()),
+            ConfigValue::List(items) => Value::Array(items.iter().map(|i| i.to_template()).collect()),
+            ConfigValue::Map(fields) => Value::Object(
+                fields
+                    .iter()
+                    .map(|(k, v)| (k.clone(), v.to_template()))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Lists every scalar value held within this value (so that secrets nested within a
+    /// structure can be masked).
+    pub fn scalars(&self) -> Vec<String> {
+        match self {
+            ConfigValue::Null => Vec::new(),
+            ConfigValue::List(items) => items.iter().flat_map(|i| i.scalars()).collect(),
+            ConfigValue::Map(fields) => fields.values().flat_map(|v| v.scalars()).collect(),
+            scalar => vec![scalar.to_string()],
+        }
+    }
+
+    fn flatten_into(&self, prefix: &str, env: &mut HashMap<String, String>) {
+        match self {
+            ConfigValue::List(items) => {
+                for (i, item) in items.iter().enumerate() {
+                    item.flatten_into(&format!("{prefix}_{i}"), env);
+                }
+            }
+            ConfigValue::Map(fields) => {
+                for (key, value) in fields.iter() {
+                    value.flatten_into(&format!("{}_{}", prefix, env_name(key)), env);
+                }
+            }
+            scalar => {
+                env.insert(prefix.to_string(), scalar.to_string());
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigValue::Null => Ok(()),
+            ConfigValue::Bool(b) => write!(f, "{b}"),
+            ConfigValue::Number(n) => write!(f, "{n}"),
+            ConfigValue::String(s) => write!(f, "{s}"),
+            structure => write!(f, "{}", serde_json::to_string(structure).unwrap_or_default()),
+        }
+    }
+}
+
+impl From<String> for ConfigValue {
+    fn from(value: String) -> Self {
+        ConfigValue::String(value)
+    }
+}
+
+impl From<&str> for ConfigValue {
+    fn from(value: &str) -> Self {
+        ConfigValue::String(value.to_string())
+    }
+}
+
+/// Converts config into the environment variables provided to scripts. Each key is provided
+/// as-is (with structured values encoded as JSON), and every value nested within a structure
+/// is also provided as `KEY_FIELD` or `KEY_INDEX` (for example `USERS_0_NAME`).
+pub fn to_env(config: &HashMap<String, ConfigValue>) -> HashMap<String, String> {
+    let mut env = HashMap::new();
+
+    for (key, value) in config.iter() {
+        if let ConfigValue::List(_) | ConfigValue::Map(_) = value {
+            value.flatten_into(&env_name(key), &mut env);
+        }
+    }
+
+    // Keys which were provided directly take precedence over flattened values with the same name.
+    for (key, value) in config.iter() {
+        env.insert(key.clone(), value.to_string());
+    }
+
+    env
+}
+
+/// Converts config into the object which templates are rendered against.
+pub fn to_template(config: &HashMap<String, ConfigValue>) -> HashMap<String, Value> {
+    config
+        .iter()
+        .map(|(key, value)| (key.clone(), value.to_template()))
+        .collect()
+}
+
+fn env_name(key: &str) -> String {
+    key.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(content: &str) -> HashMap<String, ConfigValue> {
+        serde_yaml::from_str(content).expect("the config should parse")
+    }
+
+    #[test]
+    fn env_flattening() {
+        let config = parse("users:\n  - name: alice\n    admin: true\n  - name: bob\nport: 8080\n");
+        let env = to_env(&config);
+
+        assert_eq!(env.get("USERS_0_NAME").map(|v| v.as_str()), Some("alice"));
+        assert_eq!(env.get("USERS_0_ADMIN").map(|v| v.as_str()), Some("true"));
+        assert_eq!(env.get("USERS_1_NAME").map(|v| v.as_str()), Some("bob"));
+        assert_eq!(env.get("port").map(|v| v.as_str()), Some("8080"));
+        assert_eq!(
+            env.get("users").map(|v| v.as_str()),
+            Some(r#"[{"admin":true,"name":"alice"},{"name":"bob"}]"#)
+        );
+    }
+
+    #[test]
+    fn template_structure() {
+        let config = parse("users:\n  - name: alice\nport: 8080\n");
+
+        match config["users"].to_template() {
+            Value::Array(users) => assert_eq!(users.len(), 1),
+            other => panic!("the users should be a list, but got {:?}", other),
+        }
+
+        assert_eq!(config["port"].to_template(), Value::from(8080_i64));
+    }
+
+    #[test]
+    fn scalars() {
+        let config = parse("db:\n  password: hunter2\n  hosts: [a, b]\n");
+
+        let mut scalars = config["db"].scalars();
+        scalars.sort();
+        assert_eq!(scalars, vec!["a", "b", "hunter2"]);
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use gethostname::gethostname;
+use gtmpl::Value;
+use sha2::{Digest, Sha256};
+
+use super::config_value::{to_template, ConfigValue};
+use super::package::Package;
+
+/// The identifier of this run of Buckle, generated the first time it is needed.
+static RUN_ID: OnceLock<String> = OnceLock::new();
+
+/// Builds the context which a package's templates are rendered against. The package's config and
+/// secrets are available as `.Config` and `.Secrets`, alongside `.Package`, `.Host` and `.Buckle`.
+///
+/// Config and secrets are also available at the top level (as `.KEY`), where they take precedence
+/// over the structured fields so that existing templates continue to render the same way.
+///
+/// Everything here is stable between runs except `.Buckle.RunID`, so any file which renders it
+/// will be rewritten every time its package is applied.
+pub fn template_context(
+    package: &Package,
+    config: &HashMap<String, ConfigValue>,
+    secrets: &HashMap<String, ConfigValue>,
+) -> HashMap<String, Value> {
+    let mut context = HashMap::new();
+    context.insert("Config".to_string(), Value::Object(to_template(config)));
+    context.insert("Secrets".to_string(), Value::Object(to_template(secrets)));
+    context.insert("Package".to_string(), package_facts(package));
+    context.insert("Host".to_string(), host_facts());
+    context.insert("Buckle".to_string(), buckle_facts());
+
+    context.extend(to_template(config));
+    context.extend(to_template(secrets));
+
+    context
+}
+
+/// A unique identifier for this run of Buckle, which is shared by everything it renders. It changes
+/// on every run, so templates which use it are never considered unchanged.
+pub fn run_id() -> &'static str {
+    RUN_ID.get_or_init(|| {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+
+        let mut hasher = Sha256::new();
+        hasher.update(now.to_le_bytes());
+        hasher.update(std::process::id().to_le_bytes());
+        hasher.update(gethostname().to_string_lossy().as_bytes());
+
+        format!("{:x}", hasher.finalize())[..16].to_string()
+    })
+}
+
+fn package_facts(package: &Package) -> Value {
+    let files = package
+        .files
+        .iter()
+        .map(|(group, files)| (group.clone(), Value::String(files.path.display().to_string())))
+        .collect();
+
+    let mut facts = HashMap::new();
+    facts.insert("ID".to_string(), Value::String(package.id.clone()));
+    facts.insert("Description".to_string(), Value::String(package.description.clone()));
+    facts.insert("Needs".to_string(), Value::Array(package.needs.iter().cloned().map(Value::String).collect()));
+    facts.insert("Files".to_string(), Value::Object(files));
+    Value::Object(facts)
+}
+
+fn host_facts() -> Value {
+    let mut facts = HashMap::new();
+    facts.insert("Hostname".to_string(), Value::String(gethostname().to_string_lossy().trim().to_string()));
+    facts.insert("OS".to_string(), Value::String(std::env::consts::OS.to_string()));
+    facts.insert("Arch".to_string(), Value::String(std::env::consts::ARCH.to_string()));
+    Value::Object(facts)
+}
+
+fn buckle_facts() -> Value {
+    let mut facts = HashMap::new();
+    facts.insert("Version".to_string(), Value::String(version!().to_string()));
+    facts.insert("RunID".to_string(), Value::String(run_id().to_string()));
+    Value::Object(facts)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test::get_test_data;
+
+    use super::*;
+
+    fn field<'a>(value: &'a Value, name: &str) -> &'a Value {
+        match value {
+            Value::Object(fields) => fields.get(name).unwrap_or_else(|| panic!("the field {:?} should be present", name)),
+            other => panic!("expected an object but found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn structured_context() {
+        let package = Package::load(&get_test_data().join("packages").join("test1")).expect("the package should be loaded");
+
+        let mut config = HashMap::new();
+        config.insert("enabled".to_string(), ConfigValue::from("true"));
+        config.insert("Host".to_string(), ConfigValue::from("legacy"));
+        let mut secrets = HashMap::new();
+        secrets.insert("MAGIC".to_string(), ConfigValue::from("flash"));
+
+        let context = Value::Object(template_context(&package, &config, &secrets));
+
+        assert_eq!(field(&context, "enabled"), &Value::from("true"), "config should be available at the top level");
+        assert_eq!(field(field(&context, "Config"), "enabled"), &Value::from("true"));
+        assert_eq!(field(field(&context, "Secrets"), "MAGIC"), &Value::from("flash"));
+        assert_eq!(field(field(&context, "Package"), "ID"), &Value::from("test1"));
+        assert_eq!(field(field(field(&context, "Package"), "Files"), "conf.d"), &Value::from("/etc/test.conf"));
+        assert_eq!(field(&context, "Host"), &Value::from("legacy"), "existing config keys should take precedence");
+        assert_eq!(field(field(&context, "Buckle"), "RunID"), &Value::from(run_id()));
+        assert_eq!(run_id().len(), 16);
+        assert_eq!(field(&host_facts(), "OS"), &Value::from(std::env::consts::OS));
+    }
+}
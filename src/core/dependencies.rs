@@ -0,0 +1,211 @@
+use std::collections::{BTreeMap, HashSet};
+
+/// A problem which prevents a valid execution order from being calculated for a set of packages.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DependencyProblem {
+    /// A package `needs` another package which does not exist.
+    Missing { package: String, dependency: String },
+    /// A set of packages depend on one another, listed in the order they are reached
+    /// and ending with the package which closes the cycle.
+    Cycle(Vec<String>),
+}
+
+impl std::fmt::Display for DependencyProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DependencyProblem::Missing {
+                package,
+                dependency,
+            } => write!(
+                f,
+                "The package '{package}' needs '{dependency}', but no package with that name exists."
+            ),
+            DependencyProblem::Cycle(path) => {
+                write!(f, "The packages have a circular dependency: {}", path.join(" -> "))
+            }
+        }
+    }
+}
+
+/// The dependency graph formed by the `needs` of a set of packages.
+#[derive(Debug, Default)]
+pub struct DependencyGraph {
+    nodes: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Visit {
+    InProgress,
+    Done,
+}
+
+impl DependencyGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, package: &str, needs: &[String]) {
+        self.nodes.insert(package.to_string(), needs.to_vec());
+    }
+
+    /// Calculates the order in which packages should be applied so that every package is
+    /// applied after the packages it needs. If this is not possible, every missing
+    /// dependency and circular reference is reported.
+    pub fn resolve(&self) -> Result<Vec<String>, Vec<DependencyProblem>> {
+        let mut problems = Vec::new();
+
+        for (package, needs) in self.nodes.iter() {
+            for dependency in needs.iter() {
+                if !self.nodes.contains_key(dependency) {
+                    problems.push(DependencyProblem::Missing {
+                        package: package.clone(),
+                        dependency: dependency.clone(),
+                    });
+                }
+            }
+        }
+
+        let mut order = Vec::new();
+        let mut visits = BTreeMap::new();
+        let mut stack = Vec::new();
+        let mut cycles = HashSet::new();
+
+        for package in self.nodes.keys() {
+            self.visit(package, &mut visits, &mut stack, &mut order, &mut |cycle| {
+                // The same cycle may be reached from several starting points, so we only
+                // report it the first time we encounter its set of packages.
+                let mut members: Vec<String> = cycle.iter().skip(1).cloned().collect();
+                members.sort();
+                if cycles.insert(members) {
+                    problems.push(DependencyProblem::Cycle(cycle));
+                }
+            });
+        }
+
+        if problems.is_empty() {
+            Ok(order)
+        } else {
+            Err(problems)
+        }
+    }
+
+    /// Determines the set of packages made up of `packages` and everything they
+    /// (transitively) need.
+    pub fn with_dependencies(&self, packages: &[String]) -> HashSet<String> {
+        let mut selected = HashSet::new();
+        let mut pending: Vec<&String> = packages.iter().collect();
+
+        while let Some(package) = pending.pop() {
+            if selected.insert(package.clone()) {
+                if let Some(needs) = self.nodes.get(package) {
+                    pending.extend(needs.iter());
+                }
+            }
+        }
+
+        selected
+    }
+
+    fn visit<'a>(
+        &'a self,
+        package: &'a str,
+        visits: &mut BTreeMap<&'a str, Visit>,
+        stack: &mut Vec<&'a str>,
+        order: &mut Vec<String>,
+        on_cycle: &mut dyn FnMut(Vec<String>),
+    ) {
+        match visits.get(package) {
+            Some(Visit::Done) => return,
+            Some(Visit::InProgress) => {
+                let start = stack.iter().position(|&p| p == package).unwrap_or(0);
+                let mut cycle: Vec<String> = stack[start..].iter().map(|p| p.to_string()).collect();
+                cycle.push(package.to_string());
+                on_cycle(cycle);
+                return;
+            }
+            None => {}
+        }
+
+        let needs = match self.nodes.get(package) {
+            Some(needs) => needs,
+            None => return,
+        };
+
+        visits.insert(package, Visit::InProgress);
+        stack.push(package);
+
+        for dependency in needs.iter() {
+            self.visit(dependency, visits, stack, order, on_cycle);
+        }
+
+        stack.pop();
+        visits.insert(package, Visit::Done);
+        order.push(package.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn graph(packages: &[(&str, &[&str])]) -> DependencyGraph {
+        let mut graph = DependencyGraph::new();
+        for (package, needs) in packages {
+            let needs: Vec<String> = needs.iter().map(|n| n.to_string()).collect();
+            graph.add(package, &needs);
+        }
+        graph
+    }
+
+    #[test]
+    fn resolve_order() {
+        let order = graph(&[("c", &["b"]), ("b", &["a"]), ("a", &[]), ("d", &[])])
+            .resolve()
+            .expect("the graph should resolve");
+
+        assert_eq!(order, vec!["a", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn with_dependencies() {
+        let graph = graph(&[("c", &["b"]), ("b", &["a"]), ("a", &[]), ("d", &[])]);
+
+        let mut selected: Vec<String> = graph.with_dependencies(&["c".to_string()]).into_iter().collect();
+        selected.sort();
+        assert_eq!(selected, vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn resolve_missing() {
+        let problems = graph(&[("a", &["x"]), ("b", &["a", "y"])])
+            .resolve()
+            .expect_err("the graph should not resolve");
+
+        assert_eq!(
+            problems,
+            vec![
+                DependencyProblem::Missing {
+                    package: "a".to_string(),
+                    dependency: "x".to_string()
+                },
+                DependencyProblem::Missing {
+                    package: "b".to_string(),
+                    dependency: "y".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn resolve_cycle() {
+        let problems = graph(&[("a", &["b"]), ("b", &["c"]), ("c", &["a"]), ("d", &["a"])])
+            .resolve()
+            .expect_err("the graph should not resolve");
+
+        assert_eq!(problems.len(), 1, "the cycle should only be reported once");
+        assert_eq!(
+            problems[0].to_string(),
+            "The packages have a circular dependency: a -> b -> c -> a"
+        );
+    }
+}
@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use similar::TextDiff;
+
+use super::config_value::ConfigValue;
+
+/// Renders a unified diff between the content currently present at `path` (if any)
+/// and the content which Buckle would place there.
+pub fn unified_diff(path: &Path, existing: Option<&[u8]>, desired: &[u8]) -> String {
+    let old = match existing {
+        Some(content) => match std::str::from_utf8(content) {
+            Ok(content) => content,
+            Err(_) => return format!("Binary file '{}' differs\n", path.display()),
+        },
+        None => "",
+    };
+
+    let new = match std::str::from_utf8(desired) {
+        Ok(content) => content,
+        Err(_) => return format!("Binary file '{}' differs\n", path.display()),
+    };
+
+    let old_name = if existing.is_some() {
+        path.display().to_string()
+    } else {
+        "/dev/null".to_string()
+    };
+    let new_name = path.display().to_string();
+
+    TextDiff::from_lines(old, new)
+        .unified_diff()
+        .context_radius(3)
+        .header(&old_name, &new_name)
+        .to_string()
+}
+
+/// Replaces every occurrence of a secret value within `content` with a mask so that
+/// it can be safely shown to an operator.
+pub fn mask_secrets(content: &str, secrets: &HashMap<String, ConfigValue>) -> String {
+    super::redact::mask(content, secrets.values().flat_map(|v| v.scalars()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_new_file() {
+        let diff = unified_diff(Path::new("/etc/test.conf"), None, b"enabled = true\n");
+
+        assert!(diff.contains("--- /dev/null"), "the diff should show that the file is new");
+        assert!(diff.contains("+++ /etc/test.conf"), "the diff should name the target file");
+        assert!(diff.contains("+enabled = true"), "the diff should show the added line");
+    }
+
+    #[test]
+    fn diff_modified_file() {
+        let diff = unified_diff(
+            Path::new("/etc/test.conf"),
+            Some(b"enabled = false\nport = 80\n"),
+            b"enabled = true\nport = 80\n",
+        );
+
+        assert!(diff.contains("-enabled = false"), "the diff should show the removed line");
+        assert!(diff.contains("+enabled = true"), "the diff should show the added line");
+        assert!(diff.contains(" port = 80"), "the diff should include unchanged context");
+    }
+
+    #[test]
+    fn mask() {
+        let mut secrets = HashMap::new();
+        secrets.insert("MAGIC".to_string(), "flash".into());
+        secrets.insert("MAGIC_LONG".to_string(), "flashbang".into());
+        secrets.insert("EMPTY".to_string(), "".into());
+
+        assert_eq!(
+            mask_secrets("+key = flash\n+other = flashbang\n", &secrets),
+            "+key = ******\n+other = ******\n"
+        );
+    }
+}
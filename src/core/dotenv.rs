@@ -0,0 +1,229 @@
+/// A problem encountered while parsing a dotenv file, along with the line it occurred on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DotenvError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for DotenvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for DotenvError {}
+
+/// Parses the `KEY=value` pairs in a dotenv file, in the order they are declared.
+///
+/// Supports `#` comments, an optional `export` prefix, single-quoted values (which are used
+/// literally), double-quoted values (which support `\n`, `\t`, `\r`, `\"`, `\\` and `\$` escapes)
+/// and quoted values which span multiple lines. When `skip_invalid` is set, lines which are not
+/// `KEY=value` pairs are ignored rather than being reported as errors.
+pub fn parse(content: &str, skip_invalid: bool) -> Result<Vec<(String, String)>, DotenvError> {
+    let mut pairs = Vec::new();
+    let mut lines = content.lines().enumerate().map(|(i, line)| (i + 1, line));
+
+    while let Some((number, line)) = lines.next() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let line = match line.strip_prefix("export") {
+            Some(rest) if rest.starts_with(char::is_whitespace) => rest.trim_start(),
+            _ => line,
+        };
+
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) if is_valid_key(key.trim()) => (key.trim(), value.trim_start()),
+            _ if skip_invalid => continue,
+            Some((key, _)) => {
+                return Err(DotenvError {
+                    line: number,
+                    message: format!("'{}' is not a valid variable name", key.trim()),
+                })
+            }
+            None => {
+                return Err(DotenvError {
+                    line: number,
+                    message: "expected a KEY=value pair".to_string(),
+                })
+            }
+        };
+
+        let value = match value.chars().next() {
+            Some(quote @ '"') | Some(quote @ '\'') => {
+                parse_quoted(&value[1..], quote, number, &mut lines)?
+            }
+            _ => strip_comment(value).trim_end().to_string(),
+        };
+
+        pairs.push((key.to_string(), value));
+    }
+
+    Ok(pairs)
+}
+
+/// Formats a value so that [parse] will read it back unchanged, quoting and escaping it when needed.
+pub fn quote(value: &str) -> String {
+    let needs_quotes = value != value.trim()
+        || value.contains(['\n', '\r', '\t', '"', '\'', '#']);
+
+    if !needs_quotes {
+        return value.to_string();
+    }
+
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+
+    quoted
+}
+
+fn is_valid_key(key: &str) -> bool {
+    let mut chars = key.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-')
+}
+
+/// Removes a trailing comment from an unquoted value (which must be preceded by whitespace,
+/// so that values like `http://host/#anchor` are left intact).
+fn strip_comment(value: &str) -> &str {
+    if value.starts_with('#') {
+        return "";
+    }
+
+    match value.find(" #").or_else(|| value.find("\t#")) {
+        Some(index) => &value[..index],
+        None => value,
+    }
+}
+
+fn parse_quoted<'a>(
+    first: &'a str,
+    quote: char,
+    start: usize,
+    lines: &mut impl Iterator<Item = (usize, &'a str)>,
+) -> Result<String, DotenvError> {
+    let mut value = String::new();
+    let mut current = first;
+
+    loop {
+        let mut chars = current.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                c if c == quote => {
+                    let rest = chars.as_str().trim();
+                    if !rest.is_empty() && !rest.starts_with('#') {
+                        return Err(DotenvError {
+                            line: start,
+                            message: format!("unexpected '{rest}' after the closing quote"),
+                        });
+                    }
+
+                    return Ok(value);
+                }
+                '\\' if quote == '"' => match chars.next() {
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    Some('r') => value.push('\r'),
+                    Some(escaped @ ('"' | '\\' | '$')) => value.push(escaped),
+                    Some(other) => {
+                        value.push('\\');
+                        value.push(other);
+                    }
+                    None => value.push('\\'),
+                },
+                c => value.push(c),
+            }
+        }
+
+        match lines.next() {
+            Some((_, line)) => {
+                value.push('\n');
+                current = line;
+            }
+            None => {
+                return Err(DotenvError {
+                    line: start,
+                    message: format!("the value is missing its closing {quote} quote"),
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_ok(content: &str) -> Vec<(String, String)> {
+        parse(content, false).expect("the content should parse")
+    }
+
+    fn pair(key: &str, value: &str) -> (String, String) {
+        (key.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn parse_simple() {
+        assert_eq!(
+            parse_ok("# A comment\n\nA=1\nexport B = two words  # trailing comment\nC=http://host/#anchor\nD=\n"),
+            vec![pair("A", "1"), pair("B", "two words"), pair("C", "http://host/#anchor"), pair("D", "")]
+        );
+    }
+
+    #[test]
+    fn parse_quoted() {
+        assert_eq!(
+            parse_ok("A='single $HOME \\n'\nB=\"double\\t\\\"quoted\\\"\\n\" # comment\n"),
+            vec![pair("A", "single $HOME \\n"), pair("B", "double\t\"quoted\"\n")]
+        );
+    }
+
+    #[test]
+    fn parse_multiline() {
+        assert_eq!(
+            parse_ok("CERT=\"-----BEGIN CERTIFICATE-----\nMIIB\n-----END CERTIFICATE-----\"\nNEXT=1\n"),
+            vec![
+                pair("CERT", "-----BEGIN CERTIFICATE-----\nMIIB\n-----END CERTIFICATE-----"),
+                pair("NEXT", "1")
+            ]
+        );
+    }
+
+    #[test]
+    fn quote_round_trip() {
+        for value in ["plain", "two words", "", " padded ", "line one\nline two", "say \"hi\" # now", "C:\\path", r#"{"a":[1]}"#] {
+            let content = format!("KEY={}", quote(value));
+            assert_eq!(parse_ok(&content), vec![pair("KEY", value)], "{}", content);
+        }
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(
+            parse("A=1\nnot a pair\n", false),
+            Err(DotenvError { line: 2, message: "expected a KEY=value pair".to_string() })
+        );
+
+        assert_eq!(
+            parse("A=1\nB=\"unterminated\nC=3\n", false).map_err(|e| e.line),
+            Err(2)
+        );
+
+        assert_eq!(
+            parse("A=1\nnot a pair\nB=2", true).expect("invalid lines should be skipped"),
+            vec![pair("A", "1"), pair("B", "2")]
+        );
+    }
+}
@@ -2,11 +2,16 @@ use std::path::PathBuf;
 use std::{collections::HashMap, path::Path};
 use walkdir::WalkDir;
 
-use gtmpl::{template, Value};
-use tracing::instrument;
+use gtmpl::Value;
+use sha2::{Digest, Sha256};
+use tracing::field::display;
+use tracing::{instrument, Span};
 
 use crate::errors;
 
+use super::file_group::FilePermissions;
+use super::template::{MissingKey, Partial};
+
 #[cfg(test)]
 use mocktopus::macros::*;
 
@@ -19,6 +24,44 @@ pub struct File {
     pub is_template: bool,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileState {
+    Changed,
+    Unchanged,
+}
+
+impl std::fmt::Display for FileState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FileState::Changed => write!(f, "changed"),
+            FileState::Unchanged => write!(f, "unchanged"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileChange {
+    Create,
+    Modify,
+    Unchanged,
+}
+
+impl std::fmt::Display for FileChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FileChange::Create => write!(f, "create"),
+            FileChange::Modify => write!(f, "modify"),
+            FileChange::Unchanged => write!(f, "unchanged"),
+        }
+    }
+}
+
+pub struct FilePlan {
+    pub change: FileChange,
+    pub existing: Option<Vec<u8>>,
+    pub desired: Vec<u8>,
+}
+
 #[instrument(level = "debug", name = "file.get_all", err)]
 pub fn get_all_files(dir: &Path) -> Result<Vec<File>, errors::Error> {
     let mut files = Vec::new();
@@ -102,73 +145,689 @@ pub fn get_files(dir: &Path) -> Result<Vec<File>, errors::Error> {
 #[allow(clippy::swap_ptr_to_ref)]
 #[cfg_attr(test, mockable)]
 impl File {
-    #[instrument(level = "info", name = "file.apply", fields(file.path = %self.relative_path.display()), err, skip(self, secrets))]
+    #[instrument(level = "info", name = "file.apply", fields(file.path = %self.relative_path.display(), file.state), err, skip(self, context, partials))]
     pub fn apply(
         &self,
         target: &Path,
-        config: &HashMap<String, String>,
-        secrets: &HashMap<String, String>,
-    ) -> Result<(), errors::Error> {
-        if self.is_template {
-            self.template(target, config, secrets)
+        permissions: &FilePermissions,
+        context: &HashMap<String, Value>,
+        partials: &[Partial],
+        strict: bool,
+    ) -> Result<FileState, errors::Error> {
+        let mut state = if self.is_template {
+            self.template(target, permissions, context, partials, strict)?
         } else {
-            self.copy(target)
+            self.copy(target, permissions)?
+        };
+
+        if apply_permissions(&target.join(&self.relative_path), permissions)? {
+            state = FileState::Changed;
         }
+
+        Span::current().record("file.state", display(state));
+
+        Ok(state)
     }
 
-    #[instrument(level = "debug", name = "file.template", fields(file.path = %self.relative_path.display()), err, skip(self, secrets))]
+    #[instrument(level = "debug", name = "file.template", fields(file.path = %self.relative_path.display(), file.hash), err, skip(self, context, partials))]
     fn template(
         &self,
         target: &Path,
-        config: &HashMap<String, String>,
-        secrets: &HashMap<String, String>,
-    ) -> Result<(), errors::Error> {
+        permissions: &FilePermissions,
+        context: &HashMap<String, Value>,
+        partials: &[Partial],
+        strict: bool,
+    ) -> Result<FileState, errors::Error> {
         let output_path = target.join(&self.relative_path);
 
+        let rendered = self.render_template(context, partials, strict)?;
+
+        if is_unchanged(&output_path, rendered.as_bytes())? {
+            return Ok(FileState::Unchanged);
+        }
+
         match output_path.parent() {
             Some(path) if !path.exists() => std::fs::create_dir_all(path)?,
             _ => {}
         };
 
+        write_file(&output_path, rendered.as_bytes(), permissions, None)?;
+
+        Ok(FileState::Changed)
+    }
+
+    #[instrument(level = "debug", name = "file.plan", fields(file.path = %self.relative_path.display(), file.change), err, skip(self, context, partials))]
+    pub fn plan(
+        &self,
+        target: &Path,
+        context: &HashMap<String, Value>,
+        partials: &[Partial],
+        strict: bool,
+    ) -> Result<FilePlan, errors::Error> {
+        let output_path = target.join(&self.relative_path);
+
+        let desired = if self.is_template {
+            self.render_template(context, partials, strict)?.into_bytes()
+        } else {
+            std::fs::read(&self.source_path)?
+        };
+
+        let existing = if output_path.is_file() {
+            Some(std::fs::read(&output_path)?)
+        } else {
+            None
+        };
+
+        let change = match &existing {
+            None => FileChange::Create,
+            Some(content) if content_hash(content) == content_hash(&desired) => FileChange::Unchanged,
+            Some(_) => FileChange::Modify,
+        };
+
+        Span::current().record("file.change", display(change));
+
+        Ok(FilePlan {
+            change,
+            existing,
+            desired,
+        })
+    }
+
+    /// Renders any template expressions in this file's path within its group (like
+    /// `sites/{{ .SITE_NAME }}.conf`), returning the file with its resolved `relative_path`.
+    /// Paths are always rendered strictly, so every value they reference must be defined.
+    #[instrument(level = "debug", name = "file.resolve_path", fields(file.path = %self.relative_path.display()), err, skip(self, context))]
+    pub fn resolve_path(&self, context: &HashMap<String, Value>) -> Result<File, errors::Error> {
+        let mut relative_path = PathBuf::new();
+
+        for component in self.relative_path.iter() {
+            let component = component.to_string_lossy();
+            if !component.contains("{{") {
+                relative_path.push(component.as_ref());
+                continue;
+            }
+
+            self.check_path_references(&component, context)?;
+
+            let rendered = super::template::render(&component, &[], Value::Object(context.clone()))
+                .map_err(|e| errors::user_with_internal(
+                    format!("Could not render the path of '{}' due to a problem in its template.", self.source_path.display()),
+                    "Check that the file's name is a valid template and review the internal error message for more information.",
+                    e))?;
+
+            if rendered.is_empty() || rendered == "." || rendered == ".." || rendered.contains(['/', '\\']) {
+                return Err(errors::user(
+                    format!("The path of '{}' renders '{}' as '{}', which is not a valid file or directory name.", self.source_path.display(), component, rendered),
+                    "Make sure that the values used in your file's path are not empty and do not contain path separators."));
+            }
+
+            relative_path.push(rendered);
+        }
+
+        Ok(File {
+            relative_path,
+            ..self.clone()
+        })
+    }
+
+    /// Checks that a file's path and template can be parsed and that the template only includes
+    /// partials which exist, without rendering them. When a `context` is provided, they are also
+    /// checked for references to values which it does not define.
+    #[instrument(level = "debug", name = "file.validate", fields(file.path = %self.relative_path.display()), err, skip(self, context, partials))]
+    pub fn validate(&self, context: Option<&HashMap<String, Value>>, partials: &[Partial]) -> Result<(), errors::Error> {
+        for component in self.relative_path.iter() {
+            let component = component.to_string_lossy();
+            if !component.contains("{{") {
+                continue;
+            }
+
+            super::template::parse(&component, &[])
+                .map_err(|e| errors::user_with_internal(
+                    format!("Could not parse the path of '{}' due to a problem in its template.", self.source_path.display()),
+                    "Check that the file's name is a valid template and review the internal error message for more information.",
+                    e))?;
+
+            if let Some(context) = context {
+                self.check_path_references(&component, context)?;
+            }
+        }
+
+        if !self.is_template {
+            return Ok(());
+        }
+
         let template_content = std::fs::read_to_string(&self.source_path)?;
 
-        let mut context = HashMap::new();
-        for (key, val) in config {
-            context.insert(key.clone(), Value::String(val.clone()));
+        super::template::parse(&template_content, &[])
+            .map_err(|e| errors::user_with_internal(
+                format!("Could not parse the template '{}' due to a problem in your template.", self.source_path.display()),
+                "Check that your template is valid and review the internal error message for more information.",
+                e))?;
+
+        let missing = super::template::missing_partials(&template_content, partials);
+        if !missing.is_empty() {
+            return Err(errors::composite(
+                format!("The template '{}' includes {} partials which do not exist.", self.source_path.display(), missing.len()),
+                "Add these partials to your templates directory (or the package's partials directory).",
+                missing
+                    .into_iter()
+                    .map(|m| errors::user(
+                        format!("The template '{}' includes the partial '{}' on line {}, which does not exist.", self.source_path.display(), m.key, m.line),
+                        format!("Add a '{}.tpl' file to your templates directory (or the package's partials directory).", m.key)))
+                    .collect(),
+            ));
         }
 
-        for (key, val) in secrets {
-            context.insert(key.clone(), Value::String(val.clone()));
+        match context {
+            Some(context) => self.check_references(&template_content, context, partials),
+            None => Ok(()),
+        }
+    }
+
+    fn render_template(
+        &self,
+        context: &HashMap<String, Value>,
+        partials: &[Partial],
+        strict: bool,
+    ) -> Result<String, errors::Error> {
+        let template_content = std::fs::read_to_string(&self.source_path)?;
+
+        if strict {
+            self.check_references(&template_content, context, partials)?;
         }
 
-        let context = Value::Object(context);
+        let context = Value::Object(context.clone());
 
-        let rendered = template(&template_content, context)
+        super::template::render(&template_content, partials, context)
             .map_err(|e| errors::user_with_internal(
                 &format!("Could not render the template '{}' due to a problem in your template.", self.source_path.display()),
                 "Check that your template is valid and review the internal error message for more information.", 
-                e))?;
+                e))
+    }
 
-        std::fs::write(output_path, rendered)?;
+    /// Ensures that every value referenced by the template, and by the partials it includes, is
+    /// defined in its context (strict mode).
+    fn check_references(&self, template_content: &str, context: &HashMap<String, Value>, partials: &[Partial]) -> Result<(), errors::Error> {
+        let mut missing: Vec<(&Path, MissingKey)> = super::template::missing_keys(template_content, context)
+            .into_iter()
+            .map(|m| (self.source_path.as_path(), m))
+            .collect();
 
-        Ok(())
+        for partial in super::template::included_partials(template_content, partials) {
+            missing.extend(
+                super::template::missing_keys(&partial.content, context)
+                    .into_iter()
+                    .map(|m| (partial.source_path.as_path(), m)));
+        }
+
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        Err(errors::composite(
+            format!("The template '{}' references {} values which are not defined.", self.source_path.display(), missing.len()),
+            "Define these values in your config, or use '| default' for any which are intentionally optional.",
+            missing
+                .into_iter()
+                .map(|(path, m)| errors::user(
+                    format!("The template '{}' references '.{}' on line {}, which is not defined.", path.display(), m.key, m.line),
+                    format!("Define '{}' in your config, or use '{{{{ .{} | default \"...\" }}}}' if it is intentionally optional.", m.key, m.key)))
+                .collect(),
+        ))
+    }
+
+    /// Ensures that every value referenced by a component of this file's path is defined in its context.
+    fn check_path_references(&self, component: &str, context: &HashMap<String, Value>) -> Result<(), errors::Error> {
+        match super::template::missing_keys(component, context).into_iter().next() {
+            Some(missing) => Err(errors::user(
+                format!("The path of '{}' references '.{}', which is not defined.", self.source_path.display(), missing.key),
+                format!("Define '{}' in your config, or rename the file so that it does not reference it.", missing.key))),
+            None => Ok(()),
+        }
     }
 
-    #[instrument(level = "debug", name = "file.copy", fields(file.path = %self.relative_path.display()), err, skip(self))]
-    fn copy(&self, target: &Path) -> Result<(), errors::Error> {
+    #[instrument(level = "debug", name = "file.copy", fields(file.path = %self.relative_path.display(), file.hash), err, skip(self))]
+    fn copy(&self, target: &Path, permissions: &FilePermissions) -> Result<FileState, errors::Error> {
         let output_path = target.join(&self.relative_path);
 
+        let content = std::fs::read(&self.source_path)?;
+        if is_unchanged(&output_path, &content)? {
+            return Ok(FileState::Unchanged);
+        }
+
         match output_path.parent() {
             Some(path) if !path.exists() => std::fs::create_dir_all(path)?,
             _ => {}
         };
 
-        std::fs::copy(&self.source_path, &output_path)
+        write_file(&output_path, &content, permissions, Some(&self.source_path))
             .map_err(|e| errors::user_with_internal(
-                &format!("Failed to copy file '{}' to the target directory '{}'.", self.source_path.display(), output_path.display()),
+                format!("Failed to copy file '{}' to the target directory '{}'.", self.source_path.display(), output_path.display()),
                 "Check that you have permission to write the file to this directory and that there is space available on the drive.",
                 e))?;
 
+        Ok(FileState::Changed)
+    }
+}
+
+/// Determines whether the file at `path` already holds exactly `content` by comparing
+/// their SHA-256 hashes, recording the desired hash on the current span.
+fn is_unchanged(path: &Path, content: &[u8]) -> Result<bool, errors::Error> {
+    let desired = content_hash(content);
+    Span::current().record("file.hash", display(&desired));
+
+    if !path.is_file() {
+        return Ok(false);
+    }
+
+    let existing = std::fs::read(path)?;
+    Ok(content_hash(&existing) == desired)
+}
+
+/// Writes `content` to `path` without the file ever being visible with the wrong permissions. The
+/// content is written to a temporary file alongside `path`, which is created with a restrictive mode
+/// and given its final mode and ownership before it is renamed into place.
+///
+/// When `permissions` doesn't specify a mode, the mode of the `source` file is used (as a copy
+/// would), or otherwise the mode of the file being replaced. The ownership of the file being
+/// replaced is also kept when `permissions` doesn't specify it.
+#[cfg(unix)]
+fn write_file(path: &Path, content: &[u8], permissions: &FilePermissions, source: Option<&Path>) -> Result<(), errors::Error> {
+    use std::io::Write;
+    use std::os::unix::fs::{fchown, MetadataExt, OpenOptionsExt, PermissionsExt};
+
+    let existing = std::fs::metadata(path).ok();
+    let mode = match (permissions.mode, source) {
+        (Some(mode), _) => Some(mode.bits()),
+        (None, Some(source)) => Some(std::fs::metadata(source)?.mode() & 0o7777),
+        (None, None) => existing.as_ref().map(|m| m.mode() & 0o7777),
+    };
+
+    let (uid, gid) = resolve_ownership(permissions)?;
+    let requested_ownership = uid.is_some() || gid.is_some();
+    let uid = uid.map(|u| u.as_raw()).or_else(|| existing.as_ref().map(|m| m.uid()));
+    let gid = gid.map(|g| g.as_raw()).or_else(|| existing.as_ref().map(|m| m.gid()));
+
+    let file_name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    let temp_path = path.with_file_name(format!(".{}.buckle-{}", file_name, std::process::id()));
+
+    let write = || -> Result<(), errors::Error> {
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(if mode.is_some() { 0o600 } else { 0o666 })
+            .open(&temp_path)?;
+
+        let metadata = file.metadata()?;
+        let uid = uid.filter(|uid| *uid != metadata.uid());
+        let gid = gid.filter(|gid| *gid != metadata.gid());
+        if uid.is_some() || gid.is_some() {
+            match fchown(&file, uid, gid) {
+                Err(e) if requested_ownership => Err(errors::user_with_internal(
+                    format!("Failed to change the ownership of '{}'.", path.display()),
+                    "Make sure that buckle is running with permission to change file ownership (usually as root).",
+                    e))?,
+                _ => {}
+            }
+        }
+
+        // The mode is set after the ownership, since changing the owner clears the setuid and setgid bits.
+        if let Some(mode) = mode {
+            file.set_permissions(std::fs::Permissions::from_mode(mode))
+                .map_err(|e| errors::user_with_internal(
+                    format!("Failed to set the mode of '{}' to {:04o}.", path.display(), mode),
+                    "Make sure that you have permission to change the mode of this file.",
+                    e))?;
+        }
+
+        file.write_all(content)?;
+        file.sync_all()?;
+        std::fs::rename(&temp_path, path)?;
         Ok(())
+    };
+
+    write().inspect_err(|_| {
+        std::fs::remove_file(&temp_path).ok();
+    })
+}
+
+#[cfg(not(unix))]
+fn write_file(path: &Path, content: &[u8], _permissions: &FilePermissions, _source: Option<&Path>) -> Result<(), errors::Error> {
+    std::fs::write(path, content)?;
+    Ok(())
+}
+
+/// Ensures that the file at `path` has the mode and ownership described by `permissions`,
+/// returning whether any changes were made.
+#[cfg(unix)]
+#[instrument(level = "debug", name = "file.permissions", fields(file.mode, file.owner, file.group), err)]
+fn apply_permissions(path: &Path, permissions: &FilePermissions) -> Result<bool, errors::Error> {
+    use nix::unistd::chown;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    if permissions.is_empty() {
+        return Ok(false);
+    }
+
+    let metadata = std::fs::metadata(path)?;
+    let mut changed = false;
+
+    if let Some(mode) = permissions.mode {
+        Span::current().record("file.mode", display(mode));
+
+        if metadata.mode() & 0o7777 != mode.bits() {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode.bits()))
+                .map_err(|e| errors::user_with_internal(
+                    format!("Failed to set the mode of '{}' to {}.", path.display(), mode),
+                    "Make sure that you have permission to change the mode of this file.",
+                    e))?;
+            changed = true;
+        }
+    }
+
+    let (uid, gid) = resolve_ownership(permissions)?;
+    let uid = uid.filter(|uid| uid.as_raw() != metadata.uid());
+    let gid = gid.filter(|gid| gid.as_raw() != metadata.gid());
+
+    if uid.is_some() || gid.is_some() {
+        chown(path, uid, gid).map_err(|e| errors::user_with_internal(
+            format!("Failed to change the ownership of '{}'.", path.display()),
+            "Make sure that buckle is running with permission to change file ownership (usually as root).",
+            e))?;
+        changed = true;
+    }
+
+    Ok(changed)
+}
+
+#[cfg(not(unix))]
+fn apply_permissions(path: &Path, permissions: &FilePermissions) -> Result<bool, errors::Error> {
+    if !permissions.is_empty() {
+        warn!("File modes and ownership are not supported on this platform and will not be applied to '{}'.", path.display());
+    }
+
+    Ok(false)
+}
+
+/// Resolves the owner and group described by `permissions` into the IDs used by the operating system.
+#[cfg(unix)]
+fn resolve_ownership(permissions: &FilePermissions) -> Result<(Option<nix::unistd::Uid>, Option<nix::unistd::Gid>), errors::Error> {
+    use nix::unistd::{Gid, Group, Uid, User};
+
+    let uid = match &permissions.owner {
+        Some(owner) => {
+            Span::current().record("file.owner", display(owner));
+            Some(resolve_id(owner, "user", |name| User::from_name(name).map(|u| u.map(|u| u.uid)), Uid::from_raw)?)
+        }
+        None => None,
+    };
+
+    let gid = match &permissions.group {
+        Some(group) => {
+            Span::current().record("file.group", display(group));
+            Some(resolve_id(group, "group", |name| Group::from_name(name).map(|g| g.map(|g| g.gid)), Gid::from_raw)?)
+        }
+        None => None,
+    };
+
+    Ok((uid, gid))
+}
+
+/// Resolves a user or group name (or numeric ID) into the ID used by the operating system.
+#[cfg(unix)]
+fn resolve_id<T>(
+    name: &str,
+    kind: &str,
+    lookup: impl FnOnce(&str) -> nix::Result<Option<T>>,
+    from_raw: impl FnOnce(u32) -> T,
+) -> Result<T, errors::Error> {
+    if let Ok(id) = name.parse::<u32>() {
+        return Ok(from_raw(id));
+    }
+
+    lookup(name)
+        .map_err(|e| errors::system_with_internal(
+            format!("Failed to look up the {kind} '{name}'."),
+            "Read the internal error message and take the appropriate steps to resolve the issue.",
+            e))?
+        .ok_or_else(|| errors::user(
+            format!("The {kind} '{name}' does not exist on this host."),
+            format!("Make sure that the {kind} is created before this package is applied, or update the package's file permissions.")))
+}
+
+pub fn content_hash(content: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(content))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test::get_test_data;
+
+    use super::*;
+
+    #[test]
+    fn apply_copy_is_idempotent() {
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("source.conf");
+        std::fs::write(&source, "enabled = true").unwrap();
+
+        let file = File {
+            group: "conf.d".to_string(),
+            relative_path: PathBuf::from("test.conf"),
+            source_path: source,
+            is_template: false,
+        };
+
+        let target = temp.path().join("target");
+        let context = HashMap::new();
+
+        let permissions = FilePermissions::default();
+
+        assert_eq!(
+            file.apply(&target, &permissions, &context, &[], false).expect("the file should be copied"),
+            FileState::Changed,
+            "the first copy should write the file"
+        );
+        assert_eq!(
+            file.apply(&target, &permissions, &context, &[], false).expect("the file should be copied"),
+            FileState::Unchanged,
+            "the second copy should leave the file untouched"
+        );
+    }
+
+    #[test]
+    fn apply_template_is_idempotent() {
+        let temp = tempfile::tempdir().unwrap();
+        let files = get_all_files(&get_test_data().join("packages").join("test1").join("files"))
+            .expect("the files should be loaded");
+        let file = files.first().expect("there should be a template file");
+
+        let mut context = HashMap::new();
+        context.insert("enabled".to_string(), Value::from("true"));
+        let permissions = FilePermissions::default();
+
+        assert_eq!(
+            file.apply(temp.path(), &permissions, &context, &[], true).expect("the template should be rendered"),
+            FileState::Changed,
+            "the first render should write the file"
+        );
+        assert_eq!(
+            file.apply(temp.path(), &permissions, &context, &[], true).expect("the template should be rendered"),
+            FileState::Unchanged,
+            "rendering the same content again should leave the file untouched"
+        );
+
+        context.insert("enabled".to_string(), Value::from("false"));
+        assert_eq!(
+            file.apply(temp.path(), &permissions, &context, &[], true).expect("the template should be rendered"),
+            FileState::Changed,
+            "rendering different content should rewrite the file"
+        );
+    }
+
+    #[test]
+    fn apply_template_with_partials() {
+        let temp = tempfile::tempdir().unwrap();
+        let file = File {
+            group: "motd".to_string(),
+            relative_path: PathBuf::from("motd"),
+            source_path: get_test_data().join("files").join("motd.tpl"),
+            is_template: true,
+        };
+        let partials = super::super::template::get_partials(&get_test_data().join("templates"))
+            .expect("the partials should be loaded");
+        let permissions = FilePermissions::default();
+
+        let mut context = HashMap::new();
+        let err = file
+            .apply(temp.path(), &permissions, &context, &partials, true)
+            .expect_err("strict rendering should check the partials which are included");
+        assert!(err.description().contains("motd.tpl' references '.HOSTNAME' on line 1"), "{}", err.description());
+
+        context.insert("HOSTNAME".to_string(), Value::from("example"));
+        assert_eq!(
+            file.apply(temp.path(), &permissions, &context, &partials, true).expect("the template should be rendered"),
+            FileState::Changed,
+        );
+        assert_eq!(
+            file.apply(temp.path(), &permissions, &context, &partials, true).expect("the template should be rendered"),
+            FileState::Unchanged,
+        );
+    }
+
+    #[test]
+    fn render_strict() {
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("app.conf.tpl");
+        std::fs::write(&source, "listen = {{ .IP_ADRESS }}\nport = {{ .PORT | default \"80\" }}").unwrap();
+
+        let file = File {
+            group: "conf.d".to_string(),
+            relative_path: PathBuf::from("app.conf"),
+            source_path: source,
+            is_template: true,
+        };
+
+        let mut context = HashMap::new();
+        context.insert("IP_ADDRESS".to_string(), Value::from("10.0.0.1"));
+
+        let err = file
+            .plan(temp.path(), &context, &[], true)
+            .err()
+            .expect("strict rendering should fail");
+        assert!(err.description().contains("references '.IP_ADRESS' on line 1"), "{}", err.description());
+
+        file.plan(temp.path(), &context, &[], false)
+            .expect("lenient rendering should succeed");
+    }
+
+    #[test]
+    fn resolve_path() {
+        let file = File {
+            group: "sites".to_string(),
+            relative_path: PathBuf::from("{{ .SITE_DIR }}").join("{{ .SITE_NAME }}.conf"),
+            source_path: PathBuf::from("{{ .SITE_DIR }}/{{ .SITE_NAME }}.conf.tpl"),
+            is_template: true,
+        };
+
+        let mut context = HashMap::new();
+        context.insert("SITE_DIR".to_string(), Value::from("sites-enabled"));
+        context.insert("SITE_NAME".to_string(), Value::from("example"));
+
+        let resolved = file.resolve_path(&context).expect("the path should be rendered");
+        assert_eq!(resolved.relative_path, PathBuf::from("sites-enabled").join("example.conf"));
+        assert_eq!(resolved.source_path, file.source_path, "the source path should not be changed");
+
+        let err = file
+            .resolve_path(&HashMap::new())
+            .err()
+            .expect("undefined values in a path should be rejected");
+        assert!(err.description().contains("references '.SITE_DIR'"), "{}", err.description());
+
+        context.insert("SITE_DIR".to_string(), Value::from(".."));
+        assert!(file.resolve_path(&context).is_err(), "paths should not be able to escape their directory");
+
+        let literal = File {
+            relative_path: PathBuf::from("nginx.conf"),
+            ..file
+        };
+        assert_eq!(literal.resolve_path(&HashMap::new()).expect("the path should be unchanged").relative_path, PathBuf::from("nginx.conf"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn apply_keeps_existing_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("app.conf.tpl");
+        std::fs::write(&source, "enabled = {{ .enabled }}").unwrap();
+
+        let file = File {
+            group: "conf.d".to_string(),
+            relative_path: PathBuf::from("app.conf"),
+            source_path: source,
+            is_template: true,
+        };
+
+        let target = temp.path().join("target");
+        std::fs::create_dir_all(&target).unwrap();
+        std::fs::write(target.join("app.conf"), "enabled = false").unwrap();
+        std::fs::set_permissions(target.join("app.conf"), std::fs::Permissions::from_mode(0o640)).unwrap();
+
+        let mut context = HashMap::new();
+        context.insert("enabled".to_string(), Value::from("true"));
+
+        assert_eq!(
+            file.apply(&target, &FilePermissions::default(), &context, &[], false).expect("the template should be rendered"),
+            FileState::Changed,
+        );
+
+        let mode = std::fs::metadata(target.join("app.conf")).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o640, "the mode of the replaced file should be kept");
+        assert_eq!(std::fs::read_dir(&target).unwrap().count(), 1, "no temporary files should be left behind");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn apply_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("id_ed25519");
+        std::fs::write(&source, "private key").unwrap();
+
+        let file = File {
+            group: "ssh".to_string(),
+            relative_path: PathBuf::from("id_ed25519"),
+            source_path: source,
+            is_template: false,
+        };
+
+        let target = temp.path().join("target");
+        let context = HashMap::new();
+        let permissions = FilePermissions {
+            mode: Some(0o600.into()),
+            ..Default::default()
+        };
+
+        file.apply(&target, &permissions, &context, &[], false)
+            .expect("the file should be copied");
+
+        let mode = std::fs::metadata(target.join("id_ed25519")).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o600, "the file should have the requested mode");
+
+        assert_eq!(
+            file.apply(&target, &permissions, &context, &[], false).expect("the file should be copied"),
+            FileState::Unchanged,
+            "the file should be unchanged once its content and mode match"
+        );
+
+        std::fs::set_permissions(target.join("id_ed25519"), std::fs::Permissions::from_mode(0o644)).unwrap();
+        assert_eq!(
+            file.apply(&target, &permissions, &context, &[], false).expect("the file should be copied"),
+            FileState::Changed,
+            "the file should be changed when its mode is corrected"
+        );
     }
 }
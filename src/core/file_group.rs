@@ -0,0 +1,268 @@
+use std::path::{Path, PathBuf};
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// Describes where the files within a `files/<group>` directory should be placed
+/// on the host, along with the permissions they should be given.
+///
+/// A file group may be declared either as a bare path (`confd: /etc/myservice.d`)
+/// or as an object with `path`, `mode`, `owner`, `group` and `overrides` fields.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "FileGroupDefinition")]
+pub struct FileGroup {
+    pub path: PathBuf,
+    #[serde(flatten)]
+    pub permissions: FilePermissions,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub overrides: Vec<FileOverride>,
+}
+
+impl FileGroup {
+    /// Resolves the permissions which apply to a file at `relative_path` within this group,
+    /// layering every matching override (in the order they are declared) on top of the
+    /// group's own permissions.
+    pub fn permissions_for(&self, relative_path: &Path) -> FilePermissions {
+        self.overrides
+            .iter()
+            .filter(|o| o.glob.matches(relative_path))
+            .fold(self.permissions.clone(), |permissions, o| {
+                permissions.overlay(&o.permissions)
+            })
+    }
+}
+
+impl From<PathBuf> for FileGroup {
+    fn from(path: PathBuf) -> Self {
+        Self {
+            path,
+            permissions: FilePermissions::default(),
+            overrides: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FileGroupDefinition {
+    Path(PathBuf),
+    Detailed {
+        path: PathBuf,
+        #[serde(flatten)]
+        permissions: FilePermissions,
+        #[serde(default)]
+        overrides: Vec<FileOverride>,
+    },
+}
+
+impl From<FileGroupDefinition> for FileGroup {
+    fn from(definition: FileGroupDefinition) -> Self {
+        match definition {
+            FileGroupDefinition::Path(path) => path.into(),
+            FileGroupDefinition::Detailed {
+                path,
+                permissions,
+                overrides,
+            } => Self {
+                path,
+                permissions,
+                overrides,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileOverride {
+    pub glob: FileGlob,
+    #[serde(flatten)]
+    pub permissions: FilePermissions,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FilePermissions {
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub mode: Option<FileMode>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub owner: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub group: Option<String>,
+}
+
+impl FilePermissions {
+    pub fn is_empty(&self) -> bool {
+        self.mode.is_none() && self.owner.is_none() && self.group.is_none()
+    }
+
+    fn overlay(self, other: &FilePermissions) -> FilePermissions {
+        FilePermissions {
+            mode: other.mode.or(self.mode),
+            owner: other.owner.clone().or(self.owner),
+            group: other.group.clone().or(self.group),
+        }
+    }
+}
+
+/// A unix file mode, written in octal notation (e.g. `"0640"`).
+///
+/// Only the string form is accepted, since YAML reads an unquoted `640` as a decimal number and,
+/// once parsed, a number can't be told apart from the octal value it may have been meant as.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FileMode(u32);
+
+impl FileMode {
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+}
+
+impl From<u32> for FileMode {
+    fn from(bits: u32) -> Self {
+        Self(bits)
+    }
+}
+
+impl std::fmt::Display for FileMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:04o}", self.0)
+    }
+}
+
+impl std::str::FromStr for FileMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digits = s.trim().trim_start_matches("0o");
+        match u32::from_str_radix(digits, 8) {
+            Ok(bits) if bits <= 0o7777 => Ok(Self(bits)),
+            _ => Err(format!(
+                "'{s}' is not a valid file mode, use an octal value like '0644'."
+            )),
+        }
+    }
+}
+
+impl Serialize for FileMode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for FileMode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Text(String),
+            Number(u32),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Text(text) => text.parse().map_err(de::Error::custom),
+            Raw::Number(bits) => Err(de::Error::custom(format!(
+                "The file mode {bits} is ambiguous, write file modes as quoted octal strings, e.g. '0644'."
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FileGlob(glob::Pattern);
+
+impl FileGlob {
+    pub fn matches(&self, path: &Path) -> bool {
+        self.0.matches_path(path)
+    }
+}
+
+impl Serialize for FileGlob {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for FileGlob {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        glob::Pattern::new(&pattern)
+            .map(FileGlob)
+            .map_err(|e| de::Error::custom(format!("'{pattern}' is not a valid glob: {e}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn parse_path() {
+        let groups: HashMap<String, FileGroup> =
+            serde_yaml::from_str("confd: /etc/myservice.d").expect("the file group should parse");
+
+        let group = &groups["confd"];
+        assert_eq!(group.path, PathBuf::from("/etc/myservice.d"));
+        assert!(group.permissions.is_empty(), "no permissions should be set");
+        assert!(group.overrides.is_empty(), "no overrides should be set");
+    }
+
+    #[test]
+    fn parse_detailed() {
+        let groups: HashMap<String, FileGroup> = serde_yaml::from_str(
+            r#"
+ssh:
+  path: /home/deploy/.ssh
+  mode: 0644
+  owner: deploy
+  group: deploy
+  overrides:
+    - glob: "id_*"
+      mode: "0600"
+    - glob: "*.pub"
+      owner: root
+"#,
+        )
+        .expect("the file group should parse");
+
+        let group = &groups["ssh"];
+        assert_eq!(group.path, PathBuf::from("/home/deploy/.ssh"));
+        assert_eq!(group.permissions.mode, Some(FileMode::from(0o644)));
+        assert_eq!(group.permissions.owner.as_deref(), Some("deploy"));
+
+        assert_eq!(
+            group.permissions_for(Path::new("known_hosts")),
+            group.permissions,
+            "files without a matching override should use the group's permissions"
+        );
+
+        let key = group.permissions_for(Path::new("id_ed25519"));
+        assert_eq!(key.mode, Some(FileMode::from(0o600)));
+        assert_eq!(key.owner.as_deref(), Some("deploy"));
+
+        let public_key = group.permissions_for(Path::new("id_ed25519.pub"));
+        assert_eq!(public_key.mode, Some(FileMode::from(0o600)));
+        assert_eq!(public_key.owner.as_deref(), Some("root"));
+        assert_eq!(public_key.group.as_deref(), Some("deploy"));
+    }
+
+    #[test]
+    fn parse_invalid_mode() {
+        let result: Result<HashMap<String, FileGroup>, _> =
+            serde_yaml::from_str("confd: { path: /etc/myservice.d, mode: '0999' }");
+
+        assert!(result.is_err(), "an invalid octal mode should be rejected");
+    }
+
+    #[test]
+    fn parse_mode() {
+        let parse = |yaml: &str| serde_yaml::from_str::<FilePermissions>(yaml).map(|p| p.mode);
+
+        assert_eq!(parse("mode: \"0600\"").unwrap(), Some(FileMode::from(0o600)));
+        assert_eq!(parse("mode: 0644").unwrap(), Some(FileMode::from(0o644)), "unquoted modes with a leading zero are octal strings");
+        assert!(parse("mode: \"017777\"").is_err(), "modes with more than 12 bits should be rejected");
+
+        let err = parse("mode: 644").expect_err("bare numbers should be rejected rather than read as decimal");
+        assert!(err.to_string().contains("quoted octal strings"), "{}", err);
+        assert!(parse("mode: 0o644").is_err(), "YAML octal numbers should be rejected too");
+    }
+}
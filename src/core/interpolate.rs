@@ -0,0 +1,234 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::errors;
+
+use super::config_value::ConfigValue;
+
+/// Resolves the `${NAME}` and `${NAME:-default}` references in a set of config values.
+///
+/// References are resolved against the other values in `values` first, then against `base`
+/// (the config these values are being layered on top of). References of the form `${env:NAME}`
+/// are resolved against the process environment, and `$${` may be used to write a literal `${`.
+pub fn interpolate(
+    values: &HashMap<String, ConfigValue>,
+    base: &HashMap<String, ConfigValue>,
+) -> Result<HashMap<String, ConfigValue>, errors::Error> {
+    interpolate_with_env(values, base, &|name| std::env::var(name).ok())
+}
+
+/// Resolves references in the same way as [interpolate], looking up `${env:NAME}` references
+/// with `env` instead of reading the process environment.
+pub fn interpolate_with_env(
+    values: &HashMap<String, ConfigValue>,
+    base: &HashMap<String, ConfigValue>,
+    env: &dyn Fn(&str) -> Option<String>,
+) -> Result<HashMap<String, ConfigValue>, errors::Error> {
+    let mut interpolator = Interpolator {
+        values,
+        base,
+        env,
+        resolved: HashMap::new(),
+        resolving: Vec::new(),
+    };
+
+    let mut keys: Vec<&String> = values.keys().collect();
+    keys.sort();
+
+    for key in keys {
+        interpolator.resolve_key(key)?;
+    }
+
+    Ok(interpolator.resolved)
+}
+
+struct Interpolator<'a> {
+    values: &'a HashMap<String, ConfigValue>,
+    base: &'a HashMap<String, ConfigValue>,
+    env: &'a dyn Fn(&str) -> Option<String>,
+    resolved: HashMap<String, ConfigValue>,
+    resolving: Vec<String>,
+}
+
+impl<'a> Interpolator<'a> {
+    fn resolve_key(&mut self, key: &str) -> Result<ConfigValue, errors::Error> {
+        if let Some(value) = self.resolved.get(key) {
+            return Ok(value.clone());
+        }
+
+        if let Some(start) = self.resolving.iter().position(|k| k == key) {
+            let mut cycle: Vec<String> = self.resolving[start..].to_vec();
+            cycle.push(key.to_string());
+
+            return Err(errors::user(
+                format!("The config values {} reference each other in a cycle.", cycle.join(" -> ")),
+                "Update your config so that these values do not depend on one another.",
+            ));
+        }
+
+        let value = match self.values.get(key) {
+            Some(value) => value,
+            None => return Ok(ConfigValue::Null),
+        };
+
+        self.resolving.push(key.to_string());
+        let resolved = self.resolve_value(key, value);
+        self.resolving.pop();
+
+        let resolved = resolved?;
+        self.resolved.insert(key.to_string(), resolved.clone());
+        Ok(resolved)
+    }
+
+    fn resolve_value(&mut self, key: &str, value: &ConfigValue) -> Result<ConfigValue, errors::Error> {
+        Ok(match value {
+            ConfigValue::String(s) => ConfigValue::String(self.resolve_str(key, s)?),
+            ConfigValue::List(items) => ConfigValue::List(
+                items
+                    .iter()
+                    .map(|item| self.resolve_value(key, item))
+                    .collect::<Result<Vec<_>, _>>()?,
+            ),
+            ConfigValue::Map(fields) => ConfigValue::Map(
+                fields
+                    .iter()
+                    .map(|(k, v)| Ok((k.clone(), self.resolve_value(key, v)?)))
+                    .collect::<Result<BTreeMap<_, _>, errors::Error>>()?,
+            ),
+            other => other.clone(),
+        })
+    }
+
+    fn resolve_str(&mut self, key: &str, content: &str) -> Result<String, errors::Error> {
+        let mut output = String::new();
+        let mut rest = content;
+
+        while let Some(index) = rest.find('$') {
+            output.push_str(&rest[..index]);
+            rest = &rest[index..];
+
+            if let Some(escaped) = rest.strip_prefix("$${") {
+                output.push_str("${");
+                rest = escaped;
+                continue;
+            }
+
+            let expression = match rest.strip_prefix("${") {
+                Some(expression) => expression,
+                None => {
+                    output.push('$');
+                    rest = &rest[1..];
+                    continue;
+                }
+            };
+
+            let end = find_closing_brace(expression).ok_or_else(|| errors::user(
+                format!("The config value '{key}' contains a '${{' which is never closed."),
+                "Close the reference with a '}', or write '$${' if you meant to use a literal '${'.",
+            ))?;
+
+            output.push_str(&self.resolve_reference(key, &expression[..end])?);
+            rest = &expression[end + 1..];
+        }
+
+        output.push_str(rest);
+        Ok(output)
+    }
+
+    fn resolve_reference(&mut self, key: &str, expression: &str) -> Result<String, errors::Error> {
+        let (name, default) = match expression.split_once(":-") {
+            Some((name, default)) => (name.trim(), Some(default)),
+            None => (expression.trim(), None),
+        };
+
+        let value = if let Some(variable) = name.strip_prefix("env:") {
+            (self.env)(variable)
+        } else if self.values.contains_key(name) {
+            Some(self.resolve_key(name)?.to_string())
+        } else {
+            self.base.get(name).map(|v| v.to_string())
+        };
+
+        match (value, default) {
+            (Some(value), _) => Ok(value),
+            (None, Some(default)) => self.resolve_str(key, default),
+            (None, None) => Err(errors::user(
+                format!("The config value '{key}' references '${{{name}}}', which is not defined."),
+                format!("Define '{name}' in your config, or provide a default value using '${{{name}:-default}}'."),
+            )),
+        }
+    }
+}
+
+/// Finds the `}` which closes a reference, accounting for references nested within its default value.
+fn find_closing_brace(expression: &str) -> Option<usize> {
+    let mut depth = 0;
+    let bytes = expression.as_bytes();
+
+    for (i, &b) in bytes.iter().enumerate() {
+        match b {
+            b'{' if i > 0 && bytes[i - 1] == b'$' => depth += 1,
+            b'}' if depth == 0 => return Some(i),
+            b'}' => depth -= 1,
+            _ => {}
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(pairs: &[(&str, &str)]) -> HashMap<String, ConfigValue> {
+        pairs.iter().map(|(k, v)| (k.to_string(), ConfigValue::from(*v))).collect()
+    }
+
+    #[test]
+    fn interpolate_references() {
+        let base = config(&[("APP_NAME", "buckle")]);
+        let values = config(&[
+            ("DATA_DIR", "/srv/${APP_NAME}"),
+            ("LOG_DIR", "${DATA_DIR}/logs"),
+            ("MODE", "${MODE_OVERRIDE:-${DEFAULT_MODE:-fast}}"),
+            ("PRICE", "$5 and $${literal}"),
+        ]);
+
+        let resolved = interpolate(&values, &base).expect("the config should resolve");
+
+        assert_eq!(resolved["DATA_DIR"].to_string(), "/srv/buckle");
+        assert_eq!(resolved["LOG_DIR"].to_string(), "/srv/buckle/logs");
+        assert_eq!(resolved["MODE"].to_string(), "fast");
+        assert_eq!(resolved["PRICE"].to_string(), "$5 and ${literal}");
+    }
+
+    #[test]
+    fn interpolate_environment() {
+        let env = config(&[("BUCKLE_INTERPOLATE_TEST", "from-env")]);
+        let values = config(&[
+            ("VALUE", "${env:BUCKLE_INTERPOLATE_TEST}"),
+            ("FALLBACK", "${env:BUCKLE_INTERPOLATE_MISSING:-default}"),
+        ]);
+
+        let resolved = interpolate_with_env(&values, &HashMap::new(), &|name| env.get(name).map(|v| v.to_string()))
+            .expect("the config should resolve");
+        assert_eq!(resolved["VALUE"].to_string(), "from-env");
+        assert_eq!(resolved["FALLBACK"].to_string(), "default");
+    }
+
+    #[test]
+    fn interpolate_undefined() {
+        let values = config(&[("VALUE", "${MISSING}")]);
+
+        let err = interpolate(&values, &HashMap::new()).expect_err("the reference should not resolve");
+        assert!(err.description().contains("'${MISSING}', which is not defined"));
+    }
+
+    #[test]
+    fn interpolate_cycle() {
+        let values = config(&[("A", "${B}"), ("B", "${C}"), ("C", "${A}")]);
+
+        let err = interpolate(&values, &HashMap::new()).expect_err("the references should not resolve");
+        assert!(err.description().contains("A -> B -> C -> A"));
+    }
+}
@@ -1,6 +1,18 @@
 pub mod config;
+pub mod config_value;
+pub mod context;
+pub mod dependencies;
+pub mod diff;
+pub mod dotenv;
 pub mod file;
+pub mod file_group;
+pub mod interpolate;
 pub mod output;
 pub mod package;
+pub mod process;
+pub mod redact;
 pub mod script;
+pub mod state;
+pub mod task_config;
+pub mod template;
 pub mod retry;
@@ -3,8 +3,20 @@ use std::io::{stdout, Write};
 #[cfg(test)]
 use mocktopus::{macros::*, mocking::*};
 
-#[cfg_attr(test, mockable)]
+use super::redact::RedactingWriter;
+
 pub fn output() -> Box<dyn Write + Send> {
+    Box::new(RedactingWriter::new(console()))
+}
+
+/// Writes directly to the console, without masking secrets. This should only be used for output
+/// which the operator has explicitly asked to include their secrets in.
+pub fn unredacted_output() -> Box<dyn Write + Send> {
+    console()
+}
+
+#[cfg_attr(test, mockable)]
+fn console() -> Box<dyn Write + Send> {
     Box::new(stdout())
 }
 
@@ -12,7 +24,7 @@ pub fn output() -> Box<dyn Write + Send> {
 pub fn mock() -> mocks::MockOutput {
     let writer = mocks::MockOutput::default();
     let sacrificial_writer = writer.clone();
-    output.mock_safe(move || MockResult::Return(Box::new(sacrificial_writer.clone())));
+    console.mock_safe(move || MockResult::Return(Box::new(sacrificial_writer.clone())));
     writer
 }
 
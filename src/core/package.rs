@@ -1,15 +1,23 @@
+use itertools::Itertools;
 use serde::*;
-use solvent::DepGraph;
+use sha2::{Digest, Sha256};
 use std::fs;
 use std::{
     collections::HashMap,
     path::{Path, PathBuf},
 };
 use tracing::instrument;
+use walkdir::WalkDir;
 
 use crate::errors;
 
+use super::config::ConfigSource;
+use super::config_value::ConfigValue;
+use super::dependencies::DependencyGraph;
+use super::file_group::FileGroup;
 use super::retry::RetryConfig;
+use super::task_config::TaskConfig;
+use super::template::Partial;
 use super::{file::File, script::Script};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -20,9 +28,19 @@ pub struct Package {
     #[serde(skip_serializing_if = "Vec::is_empty", default)]
     pub needs: Vec<String>,
     #[serde(default)]
-    pub files: HashMap<String, PathBuf>,
+    pub files: HashMap<String, FileGroup>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub tasks: HashMap<String, TaskConfig>,
     #[serde(default)]
     pub retry: RetryConfig,
+    #[serde(default)]
+    pub task_retry: RetryConfig,
+    #[serde(default)]
+    pub config_retry: RetryConfig,
+    /// Whether templates must only reference values which are defined. When this is not set,
+    /// templates are rendered strictly by `plan` and `validate`, but not by `apply`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strict_templates: Option<bool>,
 
     #[serde(skip)]
     path: PathBuf,
@@ -44,27 +62,168 @@ impl Package {
         Ok(pkg)
     }
 
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
     pub fn get_tasks(&self) -> Result<Vec<Script>, errors::Error> {
-        super::script::get_all_scripts(&self.path.join("scripts"))
+        let mut tasks = super::script::get_all_scripts(&self.path.join("scripts"), &self.tasks)?;
+        for task in tasks.iter_mut() {
+            task.package = self.id.clone();
+            if let Some(workdir) = &task.config.workdir {
+                task.config.workdir = Some(self.path.join(workdir));
+            }
+        }
+
+        Ok(tasks)
+    }
+
+    /// Loads this package's config, resolving any references to the global config in `base`.
+    pub fn get_config(&self, base: &HashMap<String, ConfigValue>) -> Result<HashMap<String, ConfigValue>, errors::Error> {
+        super::config::load_all_config(&self.path.join("config"), &self.config_retry, base)
     }
 
-    pub fn get_config(&self) -> Result<HashMap<String, String>, errors::Error> {
-        super::config::load_all_config(&self.path.join("config"))
+    pub fn get_config_sources(&self, base: &HashMap<String, ConfigValue>) -> Result<Vec<ConfigSource>, errors::Error> {
+        super::config::load_sources(&self.path.join("config"), &self.config_retry, base)
     }
 
-    pub fn get_secrets(&self) -> Result<HashMap<String, String>, errors::Error> {
-        super::config::load_all_config(&self.path.join("secrets"))
+    /// Loads this package's secrets. Secret scripts are provided with the config and secrets in `base`.
+    pub fn get_secrets(&self, key: Option<&Path>, base: &HashMap<String, ConfigValue>) -> Result<HashMap<String, ConfigValue>, errors::Error> {
+        super::config::load_all_secrets(&self.path.join("secrets"), &self.config_retry, key, base)
     }
 
     pub fn get_files(&self) -> Result<Vec<File>, errors::Error> {
         super::file::get_all_files(&self.path.join("files"))
     }
+
+    /// Loads the partials which are specific to this package, from its `partials` directory.
+    pub fn get_partials(&self) -> Result<Vec<Partial>, errors::Error> {
+        super::template::get_partials(&self.path.join("partials"))
+    }
+
+    /// Calculates a hash of everything which influences how this package is applied: the
+    /// contents of its directory (package.yml, config, secrets, files, partials and scripts),
+    /// its resolved `config` (including the global values it inherits, and any returned by
+    /// config scripts) and the shared partials it inherits.
+    ///
+    /// Resolved secret values are deliberately left out, since the hash is stored in plain text
+    /// and could otherwise be used to confirm a guessed secret. Changes to the package's secret
+    /// files are still picked up by hashing its directory.
+    #[instrument(level = "debug", name = "package.hash", skip(self, config, partials), fields(package.id = %self.id), err)]
+    pub fn hash(&self, config: &HashMap<String, ConfigValue>, partials: &[Partial]) -> Result<String, errors::Error> {
+        let mut hasher = Sha256::new();
+
+        for entry in WalkDir::new(&self.path).follow_links(true).sort_by_file_name() {
+            let entry = entry.map_err(|err| errors::user_with_internal(
+                format!("Failed to read the contents of the package '{}'.", self.id),
+                "Read the internal error message and take the appropriate steps to resolve the issue.",
+                err))?;
+
+            if entry.file_type().is_file() {
+                let relative_path = entry.path().strip_prefix(&self.path).unwrap_or(entry.path());
+                hasher.update(relative_path.to_string_lossy().as_bytes());
+                hasher.update([0]);
+                hasher.update(fs::read(entry.path())?);
+                hasher.update([0]);
+            }
+        }
+
+        for (key, val) in config.iter().sorted_by(|a, b| a.0.cmp(b.0)) {
+            hasher.update(key.as_bytes());
+            hasher.update([0]);
+            hasher.update(val.to_string().as_bytes());
+            hasher.update([0]);
+        }
+
+        for partial in partials {
+            hasher.update(partial.name.as_bytes());
+            hasher.update([0]);
+            hasher.update(partial.content.as_bytes());
+            hasher.update([0]);
+        }
+
+        Ok(format!("{:x}", hasher.finalize()))
+    }
 }
 
 #[instrument(level = "debug", name = "package.load_all", err)]
 pub fn get_all_packages(dir: &Path) -> Result<Vec<Package>, errors::Error> {
-    let results = dir
-        .read_dir()
+    get_package_graph(dir).map(|(packages, _)| packages)
+}
+
+/// Loads every package in `dir` (in the order they should be applied), along with the
+/// dependency graph formed by their `needs`.
+#[instrument(level = "debug", name = "package.get_graph", err)]
+pub fn get_package_graph(dir: &Path) -> Result<(Vec<Package>, DependencyGraph), errors::Error> {
+    let mut packages_lookup: HashMap<String, Package> = HashMap::new();
+    let mut graph = DependencyGraph::new();
+
+    for path in get_package_paths(dir)? {
+        let package = Package::load(&path)?;
+        graph.add(&package.id, &package.needs);
+        packages_lookup.insert(package.id.clone(), package);
+    }
+
+    let order = graph.resolve().map_err(|problems| errors::user_with_internal(
+        "Failed to calculate a valid execution graph based on the dependencies specified in your packages.",
+        "Make sure that your packages only need packages which exist and that there are no circular references.",
+        errors::detailed_message(problems.iter().map(|p| format!(" - {p}")).join("\n"))))?;
+
+    let packages = order
+        .into_iter()
+        .filter_map(|id| packages_lookup.remove(&id))
+        .collect();
+
+    Ok((packages, graph))
+}
+
+/// Narrows an ordered list of packages down to those which have been selected (along with
+/// everything they need), less any which have been explicitly excluded. When no packages
+/// are selected, every package is included. Excluding a package which one of the remaining
+/// packages needs is an error, since that package could not be applied correctly without it.
+#[instrument(level = "debug", name = "package.select", skip(packages, graph), err)]
+pub fn select_packages(
+    packages: Vec<Package>,
+    graph: &DependencyGraph,
+    include: &[String],
+    exclude: &[String],
+) -> Result<Vec<Package>, errors::Error> {
+    for name in include.iter().chain(exclude.iter()) {
+        if !packages.iter().any(|p| &p.id == name) {
+            return Err(errors::user(
+                format!("Could not find a package with the name '{name}'."),
+                "Make sure that you have spelled the package name correctly and that it is present in your packages directory.",
+            ));
+        }
+    }
+
+    let selected = if include.is_empty() {
+        None
+    } else {
+        Some(graph.with_dependencies(include))
+    };
+
+    let packages: Vec<Package> = packages
+        .into_iter()
+        .filter(|p| selected.as_ref().map(|s| s.contains(&p.id)).unwrap_or(true))
+        .filter(|p| !exclude.contains(&p.id))
+        .collect();
+
+    for package in packages.iter() {
+        if let Some(need) = package.needs.iter().find(|need| exclude.contains(need)) {
+            return Err(errors::user(
+                format!("The package '{}' needs '{}', so it cannot be excluded.", package.id, need),
+                format!("Stop excluding '{}', or exclude '{}' as well.", need, package.id),
+            ));
+        }
+    }
+
+    Ok(packages)
+}
+
+#[instrument(level = "debug", name = "package.get_paths", err)]
+pub fn get_package_paths(dir: &Path) -> Result<Vec<PathBuf>, errors::Error> {
+    dir.read_dir()
         .map(|dirs| {
             dirs.filter_map(|dir| match dir {
                 Ok(d) => match d.file_type() {
@@ -73,55 +232,55 @@ pub fn get_all_packages(dir: &Path) -> Result<Vec<Package>, errors::Error> {
                 },
                 _ => None,
             })
+            .sorted()
+            .collect()
         })
         .map_err(|err| {
             errors::user_with_internal(
             "Failed to read the list of packages files.", 
             "Read the internal error message and take the appropriate steps to resolve the issue.", 
             err)
-        })?
-        .map(|p| Package::load(&p));
-
-    let mut packages_lookup: HashMap<String, Package> = HashMap::new();
+        })
+}
 
-    let mut depgraph: DepGraph<&str> = DepGraph::new();
+#[cfg(test)]
+mod tests {
+    use crate::test::get_test_data;
 
-    for result in results {
-        let package = result?;
+    use super::*;
 
-        packages_lookup.insert(package.id.clone(), package);
+    fn ids(packages: &[Package]) -> Vec<&str> {
+        packages.iter().map(|p| p.id.as_str()).collect()
     }
 
-    for (id, package) in packages_lookup.iter() {
-        depgraph.register_node(id);
-        depgraph.register_dependencies(id, package.needs.iter().map(|s| s.as_str()).collect());
-        depgraph.register_dependency("__complete", id);
-    }
+    #[test]
+    fn test_select() {
+        let (packages, graph) = get_package_graph(&get_test_data().join("packages")).expect("packages should be loaded");
+        assert_eq!(ids(&packages), vec!["test1", "test2"]);
 
-    let order = depgraph.dependencies_of(&"__complete").map_err(|e| errors::user_with_internal(
-        "Failed to calculate a valid execution graph based on the dependencies specified in your packages.",
-        "Make sure that your packages specify valid dependencies and that there are no circular references.",
-        e))?;
-
-    let mut packages = Vec::new();
-
-    for node in order {
-        let &node = node.map_err(|e| errors::user_with_internal(
-            "Failed to calculate a valid execution graph based on the dependencies specified in your packages.",
-            "Make sure that your packages specify valid dependencies and that there are no circular references.",
-            e))?;
-
-        match node {
-            "__complete" => {}
-            _ => {
-                let package = packages_lookup.get(node).ok_or_else(|| errors::user(
-                    &format!("Failed to find package with name '{node}' although it was present in the dependency graph."),
-                    "Make sure that this package is present, or remove the dependency from any packages which currently need it."
-                ))?;
-                packages.push(package.clone());
-            }
-        }
-    }
+        let selected = select_packages(packages.clone(), &graph, &[], &[]).expect("packages should be selected");
+        assert_eq!(ids(&selected), vec!["test1", "test2"], "all packages should be selected by default");
 
-    Ok(packages)
+        let selected = select_packages(packages.clone(), &graph, &["test2".to_string()], &[]).expect("packages should be selected");
+        assert_eq!(ids(&selected), vec!["test1", "test2"], "dependencies should be included in order");
+
+        let selected = select_packages(packages.clone(), &graph, &["test1".to_string()], &[]).expect("packages should be selected");
+        assert_eq!(ids(&selected), vec!["test1"], "dependents should not be included");
+
+        let selected = select_packages(packages.clone(), &graph, &[], &["test2".to_string()]).expect("packages should be selected");
+        assert_eq!(ids(&selected), vec!["test1"], "excluded packages should be removed");
+
+        let selected = select_packages(packages.clone(), &graph, &[], &["test1".to_string(), "test2".to_string()])
+            .expect("packages should be selected");
+        assert!(selected.is_empty(), "a package and everything which needs it may be excluded together");
+
+        let err = select_packages(packages.clone(), &graph, &["test2".to_string()], &["test1".to_string()])
+            .expect_err("excluding a package which is needed should be rejected");
+        assert!(err.description().contains("'test2' needs 'test1'"), "{}", err.description());
+
+        assert!(
+            select_packages(packages, &graph, &["missing".to_string()], &[]).is_err(),
+            "unknown packages should be reported"
+        );
+    }
 }
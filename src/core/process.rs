@@ -0,0 +1,397 @@
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Read};
+use std::process;
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// How long a process is given to exit after it has been asked to stop, before it is killed.
+pub const TERMINATION_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// The number of lines from the end of each output stream which are kept for error messages
+/// and telemetry.
+pub const OUTPUT_TAIL_LINES: usize = 100;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Describes how a child process came to an end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Completion {
+    Exited(process::ExitStatus),
+    TimedOut,
+    Interrupted,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+#[derive(Debug)]
+pub struct Execution {
+    pub completion: Completion,
+    pub stdout: OutputTail,
+    pub stderr: OutputTail,
+}
+
+/// The last few lines written to one of a process' output streams.
+#[derive(Debug, Default)]
+pub struct OutputTail {
+    lines: VecDeque<String>,
+    omitted: usize,
+}
+
+impl OutputTail {
+    fn push(&mut self, line: &str) {
+        if self.lines.len() >= OUTPUT_TAIL_LINES {
+            self.lines.pop_front();
+            self.omitted += 1;
+        }
+
+        self.lines.push_back(line.to_string());
+    }
+}
+
+impl std::fmt::Display for OutputTail {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.omitted > 0 {
+            writeln!(f, "... ({} earlier lines omitted)", self.omitted)?;
+        }
+
+        for (i, line) in self.lines.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+
+            write!(f, "{line}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Runs a command to completion, passing each line it writes to `on_line` as soon as it is
+/// written (on the calling thread). On Unix hosts the command is started in its own process
+/// group so that, if it exceeds its timeout or Buckle is interrupted, the command and every
+/// process it has started are asked to stop (and killed if they do not).
+pub fn run(
+    command: &mut process::Command,
+    timeout: Option<Duration>,
+    grace_period: Duration,
+    on_line: &mut dyn FnMut(Stream, &str),
+) -> std::io::Result<Execution> {
+    command
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped());
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+    }
+
+    let _interrupts = InterruptGuard::install();
+
+    let mut child = command.spawn()?;
+
+    let (sender, lines) = mpsc::channel();
+    let readers = vec![
+        read_in_background(child.stdout.take(), Stream::Stdout, sender.clone()),
+        read_in_background(child.stderr.take(), Stream::Stderr, sender),
+    ];
+
+    let mut stdout = OutputTail::default();
+    let mut stderr = OutputTail::default();
+    let mut record = |stream: Stream, line: String| {
+        match stream {
+            Stream::Stdout => stdout.push(&line),
+            Stream::Stderr => stderr.push(&line),
+        }
+
+        on_line(stream, &line);
+    };
+
+    let completion = wait(&mut child, timeout, grace_period, &lines, &mut record)?;
+
+    // Once the process has exited, its remaining output is read until every pipe is closed.
+    for (stream, line) in lines.iter() {
+        record(stream, line);
+    }
+
+    for reader in readers {
+        reader.join().ok();
+    }
+
+    Ok(Execution {
+        completion,
+        stdout,
+        stderr,
+    })
+}
+
+/// Determines whether Buckle has been asked to stop while running a child process.
+pub fn is_interrupted() -> bool {
+    interrupts::received().is_some()
+}
+
+fn wait(
+    child: &mut process::Child,
+    timeout: Option<Duration>,
+    grace_period: Duration,
+    lines: &mpsc::Receiver<(Stream, String)>,
+    record: &mut dyn FnMut(Stream, String),
+) -> std::io::Result<Completion> {
+    let started = Instant::now();
+
+    loop {
+        match lines.recv_timeout(POLL_INTERVAL) {
+            Ok((stream, line)) => record(stream, line),
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => std::thread::sleep(POLL_INTERVAL),
+        }
+
+        if let Some(status) = child.try_wait()? {
+            return Ok(Completion::Exited(status));
+        }
+
+        if let Some(signal) = interrupts::received() {
+            terminate(child, signal, grace_period)?;
+            return Ok(Completion::Interrupted);
+        }
+
+        if matches!(timeout, Some(timeout) if started.elapsed() >= timeout) {
+            terminate(child, interrupts::TERMINATE, grace_period)?;
+            return Ok(Completion::TimedOut);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn terminate(child: &mut process::Child, signal: i32, grace_period: Duration) -> std::io::Result<()> {
+    use nix::sys::signal::{killpg, Signal};
+    use nix::unistd::Pid;
+    use std::convert::TryFrom;
+
+    let group = Pid::from_raw(child.id() as i32);
+    let signal = Signal::try_from(signal).unwrap_or(Signal::SIGTERM);
+
+    killpg(group, signal).ok();
+
+    let deadline = Instant::now() + grace_period;
+    while Instant::now() < deadline && child.try_wait()?.is_none() {
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    // Anything left in the process group (including the children of a process which has
+    // already exited) would otherwise keep running and hold our output pipes open.
+    killpg(group, Signal::SIGKILL).ok();
+    child.wait()?;
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn terminate(child: &mut process::Child, _signal: i32, _grace_period: Duration) -> std::io::Result<()> {
+    child.kill()?;
+    child.wait()?;
+    Ok(())
+}
+
+fn read_in_background<R: Read + Send + 'static>(
+    stream: Option<R>,
+    kind: Stream,
+    lines: mpsc::Sender<(Stream, String)>,
+) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut reader = match stream {
+            Some(stream) => BufReader::new(stream),
+            None => return,
+        };
+
+        let mut line = Vec::new();
+        while let Ok(read) = reader.read_until(b'\n', &mut line) {
+            if read == 0 {
+                break;
+            }
+
+            let content = String::from_utf8_lossy(&line);
+            let content = content.trim_end_matches(&['\r', '\n'][..]).to_string();
+            if lines.send((kind, content)).is_err() {
+                break;
+            }
+
+            line.clear();
+        }
+    })
+}
+
+/// Captures Ctrl-C (and SIGTERM) for as long as it is held so that they can be forwarded to
+/// the child process group, rather than leaving the children running when Buckle exits.
+///
+/// Any interrupt received before the guard was installed is cleared, so that it only stops the
+/// command it was received during (and any retries of it), rather than every later command.
+struct InterruptGuard {
+    #[cfg(unix)]
+    previous: Vec<(nix::sys::signal::Signal, nix::sys::signal::SigAction)>,
+}
+
+#[cfg(unix)]
+impl InterruptGuard {
+    fn install() -> Self {
+        use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+
+        interrupts::clear();
+
+        let action = SigAction::new(
+            SigHandler::Handler(interrupts::record),
+            SaFlags::empty(),
+            SigSet::empty(),
+        );
+
+        let previous = vec![Signal::SIGINT, Signal::SIGTERM]
+            .into_iter()
+            .filter_map(|signal| {
+                // SAFETY: the handler only stores the signal number in an atomic.
+                unsafe { sigaction(signal, &action) }
+                    .ok()
+                    .map(|previous| (signal, previous))
+            })
+            .collect();
+
+        Self { previous }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        for (signal, previous) in self.previous.iter() {
+            // SAFETY: this restores the handler which was in place before we were installed.
+            unsafe { nix::sys::signal::sigaction(*signal, previous) }.ok();
+        }
+    }
+}
+
+#[cfg(not(unix))]
+impl InterruptGuard {
+    fn install() -> Self {
+        interrupts::clear();
+        Self {}
+    }
+}
+
+mod interrupts {
+    use std::sync::atomic::{AtomicI32, Ordering};
+
+    #[cfg(unix)]
+    pub const TERMINATE: i32 = nix::libc::SIGTERM;
+    #[cfg(not(unix))]
+    pub const TERMINATE: i32 = 15;
+
+    static RECEIVED: AtomicI32 = AtomicI32::new(0);
+
+    #[cfg(unix)]
+    pub extern "C" fn record(signal: nix::libc::c_int) {
+        RECEIVED.store(signal, Ordering::SeqCst);
+    }
+
+    pub fn clear() {
+        RECEIVED.store(0, Ordering::SeqCst);
+    }
+
+    pub fn received() -> Option<i32> {
+        match RECEIVED.load(Ordering::SeqCst) {
+            0 => None,
+            signal => Some(signal),
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_streams_output() {
+        let mut command = process::Command::new("sh");
+        command.arg("-c").arg("echo hello; echo world >&2; exit 3");
+
+        let mut lines = Vec::new();
+        let execution = run(&mut command, None, TERMINATION_GRACE_PERIOD, &mut |stream, line| {
+            lines.push((stream, line.to_string()))
+        })
+        .expect("the command should run");
+
+        match execution.completion {
+            Completion::Exited(status) => assert_eq!(status.code(), Some(3)),
+            other => panic!("the command should have exited, but {:?}", other),
+        }
+
+        lines.sort_by_key(|(_, line)| line.clone());
+        assert_eq!(
+            lines,
+            vec![
+                (Stream::Stdout, "hello".to_string()),
+                (Stream::Stderr, "world".to_string())
+            ]
+        );
+        assert_eq!(execution.stdout.to_string(), "hello");
+        assert_eq!(execution.stderr.to_string(), "world");
+    }
+
+    #[test]
+    fn run_keeps_output_tail() {
+        let mut command = process::Command::new("sh");
+        command.arg("-c").arg("i=0; while [ $i -lt 150 ]; do echo line $i; i=$((i+1)); done");
+
+        let execution = run(&mut command, None, TERMINATION_GRACE_PERIOD, &mut |_, _| {})
+            .expect("the command should run");
+
+        let stdout = execution.stdout.to_string();
+        assert!(stdout.starts_with("... (50 earlier lines omitted)\nline 50\n"));
+        assert!(stdout.ends_with("line 149"));
+    }
+
+    #[test]
+    fn run_times_out() {
+        let mut command = process::Command::new("sh");
+        command.arg("-c").arg("sleep 30 & sleep 30");
+
+        let started = Instant::now();
+        let execution = run(
+            &mut command,
+            Some(Duration::from_millis(200)),
+            Duration::from_millis(200),
+            &mut |_, _| {},
+        )
+        .expect("the command should run");
+
+        assert_eq!(execution.completion, Completion::TimedOut);
+        assert!(
+            started.elapsed() < Duration::from_secs(10),
+            "the whole process group should have been stopped"
+        );
+    }
+
+    #[test]
+    fn run_after_interrupt() {
+        interrupts::record(interrupts::TERMINATE);
+        assert!(is_interrupted(), "the interrupt should be recorded");
+
+        let mut command = process::Command::new("sh");
+        command.arg("-c").arg("sleep 0.2; echo done");
+
+        let execution = run(&mut command, None, TERMINATION_GRACE_PERIOD, &mut |_, _| {})
+            .expect("the command should run");
+
+        assert!(
+            matches!(execution.completion, Completion::Exited(status) if status.success()),
+            "an earlier interrupt should not stop the next command, but it {:?}",
+            execution.completion
+        );
+        assert_eq!(execution.stdout.to_string(), "done");
+        assert!(!is_interrupted(), "the earlier interrupt should have been cleared");
+    }
+}
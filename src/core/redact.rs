@@ -0,0 +1,150 @@
+use std::io::Write;
+use std::sync::RwLock;
+
+/// The text which replaces a secret value wherever it would otherwise be shown.
+pub const MASK: &str = "******";
+
+/// The shortest secret value which is masked. Shorter values (like `1` or `yes`) appear in so much
+/// unrelated output that masking them would mangle it without protecting anything.
+pub const MIN_SECRET_LENGTH: usize = 4;
+
+/// Every secret value which has been loaded by this process, longest first.
+static SECRETS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+
+/// Records secret values (along with the name of the secret each belongs to) so that they are
+/// masked in everything Buckle writes to the console, its error messages and its telemetry from
+/// now on. Values shorter than [MIN_SECRET_LENGTH] are not masked, and a warning is logged instead.
+pub fn register<K: AsRef<str>>(values: impl IntoIterator<Item = (K, String)>) {
+    if let Ok(mut secrets) = SECRETS.write() {
+        for (key, value) in values {
+            if value.is_empty() || secrets.contains(&value) {
+                continue;
+            }
+
+            if value.chars().count() < MIN_SECRET_LENGTH {
+                warn!(
+                    secret.key = key.as_ref(),
+                    "The secret '{}' is shorter than {} characters, so it will not be masked in Buckle's output.",
+                    key.as_ref(),
+                    MIN_SECRET_LENGTH
+                );
+                continue;
+            }
+
+            secrets.push(value);
+        }
+
+        // Longer values are masked first so that a secret which contains another secret
+        // is not left partially visible.
+        secrets.sort_by_key(|v| std::cmp::Reverse(v.len()));
+    }
+}
+
+/// Masks every registered secret value within `content`.
+pub fn redact(content: &str) -> String {
+    match SECRETS.read() {
+        Ok(secrets) => mask(content, secrets.iter()),
+        Err(_) => content.to_string(),
+    }
+}
+
+/// Masks each of the given secret values within `content`.
+pub fn mask<S: AsRef<str>>(content: &str, values: impl IntoIterator<Item = S>) -> String {
+    let mut values: Vec<S> = values
+        .into_iter()
+        .filter(|v| v.as_ref().chars().count() >= MIN_SECRET_LENGTH)
+        .collect();
+    values.sort_by_key(|v| std::cmp::Reverse(v.as_ref().len()));
+
+    let mut output = content.to_string();
+    for value in values {
+        output = output.replace(value.as_ref(), MASK);
+    }
+
+    output
+}
+
+/// A writer which masks registered secrets in everything written through it. Output is
+/// buffered until the end of each line so that a secret written in several pieces is
+/// still masked.
+pub struct RedactingWriter<W: Write> {
+    inner: W,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> RedactingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            buffer: Vec::new(),
+        }
+    }
+
+    fn write_redacted(&mut self, content: &[u8]) -> std::io::Result<()> {
+        let content = redact(&String::from_utf8_lossy(content));
+        self.inner.write_all(content.as_bytes())
+    }
+}
+
+impl<W: Write> Write for RedactingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+
+        if let Some(end) = self.buffer.iter().rposition(|&b| b == b'\n') {
+            let lines: Vec<u8> = self.buffer.drain(..=end).collect();
+            self.write_redacted(&lines)?;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if !self.buffer.is_empty() {
+            let remainder = std::mem::take(&mut self.buffer);
+            self.write_redacted(&remainder)?;
+        }
+
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for RedactingWriter<W> {
+    fn drop(&mut self) {
+        self.flush().ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mask_longest_first() {
+        let values = ["flash".to_string(), "flashbang".to_string()];
+
+        assert_eq!(mask("a flashbang and a flash", values.iter()), "a ****** and a ******");
+    }
+
+    #[test]
+    fn writer_masks_split_secrets() {
+        register(vec![("PASSWORD", "hunter2-redact-test".to_string())]);
+
+        let mut content = Vec::new();
+        {
+            let mut writer = RedactingWriter::new(&mut content);
+            write!(writer, "password=hunter2-").unwrap();
+            writeln!(writer, "redact-test").unwrap();
+            write!(writer, "trailing hunter2-redact-test").unwrap();
+        }
+
+        assert_eq!(String::from_utf8(content).unwrap(), "password=******\ntrailing ******");
+    }
+
+    #[test]
+    fn short_values_are_not_masked() {
+        register(vec![("ENABLED", "yes".to_string()), ("SHORT_PIN", "1".to_string())]);
+
+        assert_eq!(redact("yesterday, 1 task ran"), "yesterday, 1 task ran");
+        assert_eq!(mask("yesterday, 1 task ran", ["yes", "1"]), "yesterday, 1 task ran");
+    }
+}
@@ -1,15 +1,96 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::Write;
 use std::time::Duration;
 
 use serde::{Serialize, Deserialize};
+use tracing::info_span;
 
+use crate::errors;
 
-#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+/// Describes how an operation should be retried when it fails.
+///
+/// The delay before each retry grows exponentially by the `backoff` factor, is capped at
+/// `max_delay` and (when `jitter` is enabled) is randomly reduced by up to half to avoid
+/// many hosts retrying in lockstep.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct RetryConfig {
-    #[serde(default)]
     pub limit: u8,
-
-    #[serde(default)]
     pub delay: RetryDelay,
+    pub max_delay: RetryDelay,
+    pub backoff: f64,
+    pub jitter: bool,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            limit: 0,
+            delay: RetryDelay::default(),
+            max_delay: RetryDelay(60_000),
+            backoff: 2.0,
+            jitter: true,
+        }
+    }
+}
+
+impl RetryConfig {
+    /// Calculates how long to wait before the given retry (starting at 1).
+    pub fn delay_for(&self, retry: u32) -> Duration {
+        let base = self.delay.0 as f64 * self.backoff.max(1.0).powi(retry.saturating_sub(1) as i32);
+        let capped = base.min(self.max_delay.0.max(self.delay.0) as f64);
+
+        let delay = if self.jitter {
+            capped / 2.0 + capped / 2.0 * random_fraction()
+        } else {
+            capped
+        };
+
+        Duration::from_millis(delay as u64)
+    }
+
+    /// Runs `action` until it succeeds or the retry limit is reached, recording each attempt
+    /// as its own span and reporting failed attempts in the output.
+    pub fn run<T>(
+        &self,
+        scope: &str,
+        mut action: impl FnMut() -> Result<T, errors::Error>,
+    ) -> Result<T, errors::Error> {
+        let attempts = self.limit as u32 + 1;
+        let mut attempt: u32 = 1;
+
+        loop {
+            let span = info_span!("retry.attempt", retry.scope = %scope, retry.attempt = attempt, retry.limit = self.limit);
+
+            match span.in_scope(&mut action) {
+                Ok(value) => return Ok(value),
+                Err(err) if attempt >= attempts || crate::core::process::is_interrupted() => return Err(err),
+                Err(err) => {
+                    let delay = self.delay_for(attempt);
+
+                    let mut output = crate::core::output::output();
+                    writeln!(
+                        output,
+                        " ! {} failed on attempt {}/{}, retrying in {:.1}s: {}",
+                        scope,
+                        attempt,
+                        attempts,
+                        delay.as_secs_f64(),
+                        err.description()
+                    )?;
+
+                    std::thread::sleep(delay);
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+fn random_fraction() -> f64 {
+    let value = RandomState::new().build_hasher().finish();
+    (value % 10_000) as f64 / 10_000.0
 }
 
 #[derive(Debug, Copy, Clone, Serialize, Deserialize)]
@@ -26,8 +107,90 @@ impl From<u32> for RetryDelay {
     }
 }
 
-impl Into<Duration> for RetryDelay {
-    fn into(self) -> Duration {
-        Duration::from_millis(self.0 as u64)
+impl From<RetryDelay> for Duration {
+    fn from(delay: RetryDelay) -> Duration {
+        Duration::from_millis(delay.0 as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+
+    fn policy(limit: u8, delay: u32) -> RetryConfig {
+        RetryConfig {
+            limit,
+            delay: delay.into(),
+            max_delay: 4000.into(),
+            backoff: 2.0,
+            jitter: false,
+        }
+    }
+
+    #[test]
+    fn delay_backoff() {
+        let retry = policy(5, 1000);
+
+        assert_eq!(retry.delay_for(1), Duration::from_millis(1000));
+        assert_eq!(retry.delay_for(2), Duration::from_millis(2000));
+        assert_eq!(retry.delay_for(3), Duration::from_millis(4000));
+        assert_eq!(retry.delay_for(4), Duration::from_millis(4000), "the delay should be capped");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn delay_jitter() {
+        let retry = RetryConfig {
+            jitter: true,
+            ..policy(5, 1000)
+        };
+
+        for _ in 0..100 {
+            let delay = retry.delay_for(2);
+            assert!(delay >= Duration::from_millis(1000) && delay <= Duration::from_millis(2000));
+        }
+    }
+
+    #[test]
+    fn parse_legacy() {
+        let retry: RetryConfig = serde_yaml::from_str("limit: 3\ndelay: 100").expect("the retry config should parse");
+
+        assert_eq!(retry.limit, 3);
+
+        let delay = retry.delay_for(1);
+        assert!(delay >= Duration::from_millis(50) && delay <= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn run_retries_until_success() {
+        let _output = crate::core::output::mock();
+        let attempts = Cell::new(0);
+
+        let result = policy(3, 0).run("test", || {
+            attempts.set(attempts.get() + 1);
+            if attempts.get() < 3 {
+                Err(errors::user("Failed", "Try again"))
+            } else {
+                Ok(attempts.get())
+            }
+        });
+
+        assert_eq!(result.expect("the action should eventually succeed"), 3);
+    }
+
+    #[test]
+    fn run_stops_at_limit() {
+        let output = crate::core::output::mock();
+        let attempts = Cell::new(0);
+
+        let result: Result<(), errors::Error> = policy(2, 0).run("test", || {
+            attempts.set(attempts.get() + 1);
+            Err(errors::user("Failed", "Try again"))
+        });
+
+        assert!(result.is_err(), "the action should fail");
+        assert_eq!(attempts.get(), 3, "the action should be attempted once, then retried twice");
+        assert!(output.to_string().contains(" ! test failed on attempt 2/3"));
+    }
+}
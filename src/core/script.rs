@@ -1,25 +1,49 @@
 use std::path::PathBuf;
 use std::{collections::HashMap, path::Path};
 
-use itertools::Itertools;
+use gtmpl::Value;
 use std::process;
 use tracing::field::display;
 use tracing::{instrument, Span};
 
 use crate::errors;
 
+use super::config_value::{to_env, ConfigValue};
+use super::process::{Completion, Stream, TERMINATION_GRACE_PERIOD};
+use super::redact::redact;
+use super::task_config::{is_sidecar, TaskConfig};
+
 #[cfg(test)]
 use mocktopus::macros::*;
 
 #[cfg_attr(test, mockable)]
 #[derive(Clone)]
 pub struct Script {
+    pub package: String,
     pub name: String,
     pub path: PathBuf,
+    pub config: TaskConfig,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    Completed,
+    Skipped,
 }
 
-#[instrument(level = "debug", name = "script.get_all", err)]
-pub fn get_all_scripts(dir: &Path) -> Result<Vec<Script>, errors::Error> {
+impl std::fmt::Display for TaskState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TaskState::Completed => write!(f, "completed"),
+            TaskState::Skipped => write!(f, "skipped"),
+        }
+    }
+}
+
+/// Loads the tasks in a package's scripts directory, using their `<script>.yml` sidecar
+/// metadata if it is present, or the matching entry in `tasks` otherwise.
+#[instrument(level = "debug", name = "script.get_all", skip(tasks), err)]
+pub fn get_all_scripts(dir: &Path, tasks: &HashMap<String, TaskConfig>) -> Result<Vec<Script>, errors::Error> {
     if !dir.exists() {
         return Ok(Vec::new());
     }
@@ -29,7 +53,7 @@ pub fn get_all_scripts(dir: &Path) -> Result<Vec<Script>, errors::Error> {
         .map(|dirs| {
             dirs.filter_map(|dir| match dir {
                 Ok(d) => match d.file_type() {
-                    Ok(ft) if ft.is_file() => Some(d.path()),
+                    Ok(ft) if ft.is_file() && !is_sidecar(&d.path()) => Some(d.path()),
                     _ => None,
                 },
                 _ => None,
@@ -42,27 +66,41 @@ pub fn get_all_scripts(dir: &Path) -> Result<Vec<Script>, errors::Error> {
             err)
         })?;
 
-    Ok(files
-        .map(|f| Script {
-            name: f
+    let mut scripts = files
+        .map(|f| {
+            let name = f
                 .file_name()
                 .map(|n| n.to_string_lossy().to_string())
-                .unwrap(),
-            path: dunce::simplified(&f).to_owned(),
+                .unwrap();
+
+            let config = match TaskConfig::load_sidecar(&f)? {
+                Some(config) => config,
+                None => tasks.get(&name).cloned().unwrap_or_default(),
+            };
+
+            Ok(Script {
+                package: String::new(),
+                name,
+                path: dunce::simplified(&f).to_owned(),
+                config,
+            })
         })
-        .sorted_by_key(|s| s.name.clone())
-        .collect())
+        .collect::<Result<Vec<Script>, errors::Error>>()?;
+
+    scripts.sort_by_key(|s| s.name.clone());
+    Ok(scripts)
 }
 
 #[allow(clippy::swap_ptr_to_ref)]
 #[cfg_attr(test, mockable)]
 impl Script {
-    #[instrument(level = "info", name = "script.run", fields(task.name = %self.name, task.path = %self.path.display()), err, skip(self, secrets))]
+    #[instrument(level = "info", name = "script.run", fields(task.name = %self.name, task.path = %self.path.display()), err, skip(self, config, secrets, context))]
     pub fn run(
         &self,
-        config: &HashMap<String, String>,
-        secrets: &HashMap<String, String>,
-    ) -> Result<(), errors::Error> {
+        config: &HashMap<String, ConfigValue>,
+        secrets: &HashMap<String, ConfigValue>,
+        context: &HashMap<String, Value>,
+    ) -> Result<TaskState, errors::Error> {
         let extension = match self.path.extension() {
             Some(ext) => ext.to_str().ok_or_else(|| errors::user(
                 &format!("Unable to parse the file extension used by the task file '{}'", self.path.display()),
@@ -73,17 +111,20 @@ impl Script {
                 "Use one of the supported file extensions to tell buckle how to execute this task file."))?
         };
 
-        let mut config = config.clone();
-        for (key, val) in secrets {
-            config.insert(key.clone(), val.into());
+        if !self.should_run(context)? {
+            return Ok(TaskState::Skipped);
         }
 
-        match extension {
-            "ps1" => run_script_task("pwsh", &config, &self.path)?,
-            "sh" => run_script_task("bash", &config, &self.path)?,
-            "bat" => run_script_task("cmd.exe", &config, &self.path)?,
-            "cmd" => run_script_task("cmd.exe", &config, &self.path)?,
-            _ => Err(errors::user(
+        let mut env = to_env(config);
+        env.extend(to_env(secrets));
+
+        for (key, val) in self.config.env.iter() {
+            env.insert(key.clone(), val.clone());
+        }
+
+        match interpreter_for(extension) {
+            Some(interpreter) => run_script_task(interpreter, &env, &self.path, &self.config, &self.label())?,
+            None => Err(errors::user(
                 &format!(
                     "The '{extension}' extension is not supported for task files."
                 ),
@@ -91,50 +132,168 @@ impl Script {
             ))?,
         }
 
-        Ok(())
+        Ok(TaskState::Completed)
+    }
+
+    /// The prefix used to identify this task's output.
+    pub fn label(&self) -> String {
+        if self.package.is_empty() {
+            self.name.clone()
+        } else {
+            format!("{}/{}", self.package, self.name)
+        }
+    }
+
+    /// Evaluates the task's `when` condition (if it has one) against the package's template context
+    /// to determine whether it should be run.
+    pub fn should_run(&self, context: &HashMap<String, Value>) -> Result<bool, errors::Error> {
+        let condition = match &self.config.when {
+            Some(condition) => condition,
+            None => return Ok(true),
+        };
+
+        let result = super::template::render(condition, &[], Value::Object(context.clone()))
+            .map_err(|e| errors::user_with_internal(
+                format!("Could not evaluate the 'when' condition for the task '{}'.", self.name),
+                "Check that the condition is a valid template and review the internal error message for more information.",
+                e))?;
+
+        Ok(is_truthy(&result))
+    }
+}
+
+fn is_truthy(value: &str) -> bool {
+    !matches!(
+        value.trim().to_lowercase().as_str(),
+        "" | "false" | "0" | "no" | "<no value>"
+    )
+}
+
+/// Writes a line of a script's output to the console and records it as a tracing event.
+pub fn print_line(output: &mut dyn std::io::Write, label: &str, stream: Stream, line: &str) {
+    let line = redact(line);
+    match stream {
+        Stream::Stdout => {
+            info!(output.stream = "stdout", "{}", line);
+            writeln!(output, "   | [{label}] {line}").ok();
+        }
+        Stream::Stderr => {
+            warn!(output.stream = "stderr", "{}", line);
+            writeln!(output, "   ! [{label}] {line}").ok();
+        }
+    }
+}
+
+/// Determines which interpreter should be used to execute a script with the given file extension.
+pub fn interpreter_for(extension: &str) -> Option<&'static str> {
+    match extension {
+        "ps1" => Some("pwsh"),
+        "sh" => Some("bash"),
+        "bat" | "cmd" => Some("cmd.exe"),
+        _ => None,
     }
 }
 
 #[allow(clippy::swap_ptr_to_ref)]
 #[cfg_attr(test, mockable)]
-#[instrument(name = "command.run", fields(stdout, stderr), skip(env), err)]
+#[instrument(name = "command.run", fields(stdout, stderr, exit_code, timed_out), skip(env, options), err)]
 pub fn run_script_task(
     interpreter: &str,
     env: &HashMap<String, String>,
     file: &Path,
+    options: &TaskConfig,
+    label: &str,
 ) -> Result<(), errors::Error> {
-    process::Command::new(interpreter)
-        .arg(file)
-        .envs(env)
-        .output()
+    let mut command = process::Command::new(interpreter);
+    command.arg(file).envs(env);
+
+    if let Some(workdir) = &options.workdir {
+        command.current_dir(workdir);
+    }
+
+    if let Some(user) = &options.user {
+        run_as(&mut command, user)?;
+    }
+
+    let mut output = crate::core::output::output();
+    let execution = super::process::run(&mut command, options.timeout(), TERMINATION_GRACE_PERIOD, &mut |stream, line| {
+        print_line(&mut output, label, stream, line)
+    })
         .map_err(|err| errors::user_with_internal(
-            &format!("Failed to execute the command '{} {}'.", interpreter, file.display()), 
-            &format!("Make sure that '{interpreter}' is installed and present on your path and that you have permission to access it."),
-            err))
-        .and_then(|output| {
-            let stdout = String::from_utf8_lossy(&output.stdout);
-            let stderr = String::from_utf8_lossy(&output.stderr);
-
-            Span::current().record("stdout", &display(String::from_utf8_lossy(&output.stdout)));
-            Span::current().record("stderr", &display(String::from_utf8_lossy(&output.stderr)));
-
-            if output.status.success() {
-                Ok(())
-            } else {
-                Err(errors::user_with_internal(
+            format!("Failed to execute the command '{} {}'.", interpreter, file.display()), 
+            format!("Make sure that '{interpreter}' is installed and present on your path and that you have permission to access it."),
+            err))?;
+
+    let stdout = execution.stdout;
+    let stderr = execution.stderr;
+
+    Span::current().record("stdout", display(redact(&stdout.to_string())));
+    Span::current().record("stderr", display(redact(&stderr.to_string())));
+    Span::current().record("timed_out", execution.completion == Completion::TimedOut);
+
+    let details = format!("---- STDOUT: ----\n{stdout}\n\n---- STDERR: ----\n{stderr}");
+
+    match execution.completion {
+        Completion::Exited(status) => {
+            if let Some(code) = status.code() {
+                Span::current().record("exit_code", code);
+            }
+
+            match status.code() {
+                Some(code) if options.is_allowed_exit_code(code) => Ok(()),
+                _ => Err(errors::user_with_internal(
                     "Failed to run script.",
                     "Read the internal error message and take the appropriate steps to resolve the issue.",
-                    errors::detailed_message(format!(
-                        "---- STDOUT: ----\n{stdout}\n\n---- STDERR: ----\n{stderr}"))))
+                    errors::detailed_message(details))),
             }
-        })
+        }
+        Completion::TimedOut => Err(errors::timeout(
+            format!("The task '{}' did not complete within its {} second timeout and was stopped.", file.display(), options.timeout.unwrap_or_default()),
+            "Check whether the task is waiting on something which will never complete, or increase its timeout.",
+            options.timeout().unwrap_or_default(),
+            details)),
+        Completion::Interrupted => Err(errors::user_with_internal(
+            format!("The task '{}' was stopped because buckle was interrupted.", file.display()),
+            "Run buckle again once you are ready to complete the remaining tasks.",
+            errors::detailed_message(details))),
+    }
+}
+
+#[cfg(unix)]
+fn run_as(command: &mut process::Command, user: &str) -> Result<(), errors::Error> {
+    use nix::unistd::{Uid, User};
+    use std::os::unix::process::CommandExt;
+
+    let lookup = match user.parse::<u32>() {
+        Ok(id) => User::from_uid(Uid::from_raw(id)),
+        Err(_) => User::from_name(user),
+    };
+
+    let user = lookup
+        .map_err(|e| errors::system_with_internal(
+            format!("Failed to look up the user '{user}'."),
+            "Read the internal error message and take the appropriate steps to resolve the issue.",
+            e))?
+        .ok_or_else(|| errors::user(
+            format!("The user '{user}' does not exist on this host."),
+            "Make sure that the user is created before this task is run, or update the task's user."))?;
+
+    command.uid(user.uid.as_raw()).gid(user.gid.as_raw());
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn run_as(_command: &mut process::Command, user: &str) -> Result<(), errors::Error> {
+    Err(errors::user(
+        format!("Cannot run a task as the user '{user}' on this platform."),
+        "Remove the task's user option, since running tasks as another user is only supported on Unix hosts."))
 }
 
 #[cfg(test)]
 mod tests {
     use crate::test::get_test_data;
 
-    use super::get_all_scripts;
+    use super::*;
 
     #[test]
     fn test_load() {
@@ -142,7 +301,7 @@ mod tests {
             .join("packages")
             .join("test1")
             .join("scripts");
-        let scripts = get_all_scripts(&path).expect("scripts should be loaded");
+        let scripts = get_all_scripts(&path, &HashMap::new()).expect("scripts should be loaded");
 
         assert_eq!(scripts.len(), 1, "there should be 1 script in the package");
 
@@ -157,4 +316,89 @@ mod tests {
             "the script's path should be correct"
         );
     }
+
+    #[test]
+    fn test_load_metadata() {
+        let temp = tempfile::tempdir().unwrap();
+        std::fs::write(temp.path().join("a.sh"), "echo a").unwrap();
+        std::fs::write(temp.path().join("b.sh"), "echo b").unwrap();
+        std::fs::write(temp.path().join("b.sh.yml"), "timeout: 10").unwrap();
+
+        let mut tasks = HashMap::new();
+        tasks.insert("a.sh".to_string(), TaskConfig { user: Some("deploy".into()), ..Default::default() });
+        tasks.insert("b.sh".to_string(), TaskConfig { user: Some("deploy".into()), ..Default::default() });
+
+        let scripts = get_all_scripts(temp.path(), &tasks).expect("scripts should be loaded");
+        assert_eq!(scripts.len(), 2, "the sidecar file should not be treated as a script");
+
+        assert_eq!(scripts[0].config.user.as_deref(), Some("deploy"), "the package.yml metadata should be used");
+        assert_eq!(scripts[1].config.timeout, Some(10), "the sidecar metadata should be used");
+        assert_eq!(scripts[1].config.user, None, "the sidecar metadata should take precedence");
+    }
+
+    #[test]
+    fn test_when() {
+        let script = Script {
+            package: "test".into(),
+            name: "test.sh".into(),
+            path: PathBuf::from("test.sh"),
+            config: TaskConfig { when: Some("false".into()), ..Default::default() },
+        };
+
+        let state = script.run(&HashMap::new(), &HashMap::new(), &HashMap::new()).expect("the task should be skipped");
+        assert_eq!(state, TaskState::Skipped);
+
+        let package = super::super::package::Package::load(&crate::test::get_test_data().join("packages").join("test1"))
+            .expect("the package should be loaded");
+        let context = super::super::context::template_context(&package, &HashMap::new(), &HashMap::new());
+        let script = Script {
+            config: TaskConfig { when: Some("{{ .Package.ID }}".into()), ..Default::default() },
+            ..script
+        };
+        assert!(script.should_run(&context).expect("the condition should be evaluated"), "conditions should be able to use the package's fields");
+
+        assert!(is_truthy("true"));
+        assert!(is_truthy("yes"));
+        assert!(!is_truthy(" False "));
+        assert!(!is_truthy("<no value>"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_timeout() {
+        let temp = tempfile::tempdir().unwrap();
+        let script = temp.path().join("hang.sh");
+        std::fs::write(&script, "sleep 30").unwrap();
+
+        let options = TaskConfig { timeout: Some(1), ..Default::default() };
+        let err = run_script_task("sh", &HashMap::new(), &script, &options, "hang.sh")
+            .expect_err("the task should time out");
+
+        assert!(err.is_timeout(), "the error should be reported as a timeout");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_timeout_redacts_output() {
+        crate::core::redact::register(vec![("PASSWORD", "hunter2-timeout-test".to_string())]);
+
+        let temp = tempfile::tempdir().unwrap();
+        let script = temp.path().join("leak.sh");
+        std::fs::write(&script, "echo hunter2-timeout-test\nsleep 30").unwrap();
+
+        let options = TaskConfig { timeout: Some(1), ..Default::default() };
+        let err = run_script_task("sh", &HashMap::new(), &script, &options, "leak.sh")
+            .expect_err("the task should time out");
+
+        let mut messages = vec![err.to_string()];
+        let mut source = std::error::Error::source(&err);
+        while let Some(inner) = source {
+            messages.push(inner.to_string());
+            source = inner.source();
+        }
+
+        let messages = messages.join("\n");
+        assert!(!messages.contains("hunter2-timeout-test"), "the secret should not appear in the error: {}", messages);
+        assert!(messages.contains(crate::core::redact::MASK), "the secret should be masked in the error: {}", messages);
+    }
 }
@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use crate::errors;
+
+/// Records the inputs and outcome of the last time each package was applied so that
+/// subsequent runs can skip packages which have not changed.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct StateJournal {
+    #[serde(default)]
+    pub packages: HashMap<String, PackageState>,
+
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PackageState {
+    pub hash: String,
+    pub outcome: PackageOutcome,
+    pub applied_at: u64,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PackageOutcome {
+    Success,
+    Failure,
+}
+
+/// The directory in which Buckle keeps its state when no `--state-dir` is provided.
+pub fn default_state_dir() -> Option<PathBuf> {
+    directories_next::ProjectDirs::from("com", "SierraSoftworks", "buckle")
+        .map(|dirs| dirs.data_local_dir().to_owned())
+}
+
+impl StateJournal {
+    #[instrument(level = "debug", name = "state.load", err)]
+    pub fn load(dir: &Path) -> Result<StateJournal, errors::Error> {
+        let path = dir.join("state.json");
+
+        let mut journal = if path.exists() {
+            let content = std::fs::read(&path)?;
+            serde_json::from_slice(&content).unwrap_or_else(|err| {
+                warn!(
+                    "The state journal at '{}' could not be read and will be replaced: {}",
+                    path.display(),
+                    err
+                );
+                StateJournal::default()
+            })
+        } else {
+            StateJournal::default()
+        };
+
+        journal.path = path;
+        Ok(journal)
+    }
+
+    /// Saves the journal by writing it to a temporary file alongside it, which is then renamed into
+    /// place, so that the journal is never left partially written if Buckle is interrupted.
+    #[instrument(level = "debug", name = "state.save", skip(self), fields(state.path = %self.path.display()), err)]
+    pub fn save(&self) -> Result<(), errors::Error> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let content = serde_json::to_vec_pretty(self)?;
+        let temp_path = self.path.with_file_name(format!(".state.json.{}", std::process::id()));
+
+        let write = || -> std::io::Result<()> {
+            use std::io::Write;
+
+            let mut file = std::fs::File::create(&temp_path)?;
+            file.write_all(&content)?;
+            file.sync_all()?;
+            std::fs::rename(&temp_path, &self.path)
+        };
+
+        write().map_err(|e| {
+            std::fs::remove_file(&temp_path).ok();
+            errors::user_with_internal(
+                format!("Failed to write the state journal to '{}'.", self.path.display()),
+                "Make sure that you have permission to write to the state directory, or choose a different one with --state-dir.",
+                e,
+            )
+        })
+    }
+
+    /// Determines whether a package was last applied successfully with exactly the same inputs.
+    pub fn is_current(&self, package: &str, hash: &str) -> bool {
+        matches!(
+            self.packages.get(package),
+            Some(state) if state.outcome == PackageOutcome::Success && state.hash == hash
+        )
+    }
+
+    pub fn record(&mut self, package: &str, hash: &str, outcome: PackageOutcome) {
+        let applied_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        self.packages.insert(
+            package.to_string(),
+            PackageState {
+                hash: hash.to_string(),
+                outcome,
+                applied_at,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let temp = tempfile::tempdir().unwrap();
+
+        let mut journal = StateJournal::load(temp.path()).expect("an empty journal should load");
+        assert!(!journal.is_current("test1", "abc"), "an empty journal should have no packages");
+
+        journal.record("test1", "abc", PackageOutcome::Success);
+        journal.record("test2", "def", PackageOutcome::Failure);
+        journal.save().expect("the journal should be saved");
+
+        let journal = StateJournal::load(temp.path()).expect("the journal should load");
+        assert!(journal.is_current("test1", "abc"), "a successful package with the same hash is current");
+        assert!(!journal.is_current("test1", "xyz"), "a package with a different hash is not current");
+        assert!(!journal.is_current("test2", "def"), "a failed package is never current");
+
+        journal.save().expect("the journal should be saved over the existing one");
+        let files: Vec<_> = std::fs::read_dir(temp.path()).unwrap().map(|e| e.unwrap().file_name()).collect();
+        assert_eq!(files, vec![std::ffi::OsString::from("state.json")], "no temporary files should be left behind");
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::errors;
+
+use super::retry::RetryConfig;
+
+/// Describes how an individual task should be run, either through the `tasks` map in a
+/// package's `package.yml` or a `<script>.yml` file alongside the script (such as `install.sh.yml`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TaskConfig {
+    /// The number of seconds the task may run for before it is stopped.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    /// The directory the task is run in, relative to the package directory.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub workdir: Option<PathBuf>,
+    /// The user (name or numeric ID) the task should be run as.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// Additional environment variables which are provided to the task.
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub env: HashMap<String, String>,
+    /// The exit codes which indicate that the task completed successfully.
+    pub exit_codes: Vec<i32>,
+    /// The retry policy used for this task, in place of the package's `task_retry` policy.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry: Option<RetryConfig>,
+    /// A template which must render to a truthy value for the task to be run.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub when: Option<String>,
+}
+
+impl Default for TaskConfig {
+    fn default() -> Self {
+        Self {
+            timeout: None,
+            workdir: None,
+            user: None,
+            env: HashMap::new(),
+            exit_codes: vec![0],
+            retry: None,
+            when: None,
+        }
+    }
+}
+
+impl TaskConfig {
+    /// Loads the sidecar metadata file (`<script>.yml` or `<script>.yaml`) for the given script, if one exists.
+    pub fn load_sidecar(script: &Path) -> Result<Option<TaskConfig>, errors::Error> {
+        let path = match ["yml", "yaml"].iter().map(|ext| sidecar_path(script, ext)).find(|p| p.exists()) {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+
+        let content = std::fs::read(&path)?;
+        serde_yaml::from_slice(&content).map(Some).map_err(|e| {
+            errors::user_with_internal(
+                format!("The task metadata file '{}' could not be parsed.", path.display()),
+                "Make sure that the file is valid YAML and only uses the supported task options.",
+                e,
+            )
+        })
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout.map(Duration::from_secs)
+    }
+
+    pub fn is_allowed_exit_code(&self, code: i32) -> bool {
+        self.exit_codes.contains(&code)
+    }
+}
+
+/// Determines whether a file in a package's `scripts/` directory holds task metadata
+/// rather than being a task itself, which is the case when it is named after a script
+/// which exists alongside it.
+pub fn is_sidecar(path: &Path) -> bool {
+    is_metadata(path) && path.with_extension("").is_file()
+}
+
+/// Determines whether a file in a package's `scripts/` directory looks like task metadata
+/// (whether or not the script it describes exists).
+pub fn is_metadata(path: &Path) -> bool {
+    matches!(path.extension().and_then(|e| e.to_str()), Some("yml") | Some("yaml"))
+}
+
+fn sidecar_path(script: &Path, extension: &str) -> PathBuf {
+    let mut path = script.as_os_str().to_owned();
+    path.push(".");
+    path.push(extension);
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_defaults() {
+        let config: TaskConfig = serde_yaml::from_str("timeout: 30").expect("the task config should parse");
+
+        assert_eq!(config.timeout(), Some(Duration::from_secs(30)));
+        assert!(config.is_allowed_exit_code(0), "a zero exit code should be allowed by default");
+        assert!(!config.is_allowed_exit_code(1), "a non-zero exit code should not be allowed by default");
+    }
+
+    #[test]
+    fn parse_detailed() {
+        let config: TaskConfig = serde_yaml::from_str(
+            "workdir: files\nuser: deploy\nenv:\n  MODE: fast\nexit_codes: [0, 3]\nretry:\n  limit: 2\nwhen: '{{ .ENABLED }}'",
+        )
+        .expect("the task config should parse");
+
+        assert_eq!(config.workdir, Some(PathBuf::from("files")));
+        assert_eq!(config.user.as_deref(), Some("deploy"));
+        assert_eq!(config.env.get("MODE").map(|v| v.as_str()), Some("fast"));
+        assert!(config.is_allowed_exit_code(3));
+        assert_eq!(config.retry.map(|r| r.limit), Some(2));
+        assert_eq!(config.when.as_deref(), Some("{{ .ENABLED }}"));
+    }
+
+    #[test]
+    fn sidecars() {
+        let temp = tempfile::tempdir().unwrap();
+        std::fs::write(temp.path().join("setup.sh"), "echo hello").unwrap();
+        std::fs::write(temp.path().join("setup.sh.yaml"), "timeout: 10").unwrap();
+        std::fs::write(temp.path().join("removed.sh.yml"), "timeout: 10").unwrap();
+
+        assert!(is_sidecar(&temp.path().join("setup.sh.yaml")));
+        assert!(!is_sidecar(&temp.path().join("removed.sh.yml")), "metadata without a matching script should not be a sidecar");
+        assert!(is_metadata(&temp.path().join("removed.sh.yml")));
+
+        let config = TaskConfig::load_sidecar(&temp.path().join("setup.sh"))
+            .expect("the sidecar should be loaded")
+            .expect("the sidecar should be found");
+        assert_eq!(config.timeout, Some(10));
+    }
+}
@@ -0,0 +1,939 @@
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use gtmpl::error::TemplateError;
+use gtmpl::{Context, Func, FuncError, Template, Value};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tracing::instrument;
+use walkdir::WalkDir;
+
+use crate::errors;
+
+/// The helper functions available to every template, named after their
+/// [Sprig](https://masterminds.github.io/sprig/) counterparts.
+pub const FUNCTIONS: &[(&str, Func)] = &[
+    ("default", default),
+    ("empty", empty),
+    ("coalesce", coalesce),
+    ("upper", upper),
+    ("lower", lower),
+    ("title", title),
+    ("trim", trim),
+    ("trimPrefix", trim_prefix),
+    ("trimSuffix", trim_suffix),
+    ("replace", replace),
+    ("contains", contains),
+    ("hasPrefix", has_prefix),
+    ("hasSuffix", has_suffix),
+    ("split", split),
+    ("splitList", split),
+    ("join", join),
+    ("b64enc", b64enc),
+    ("b64dec", b64dec),
+    ("sha256sum", sha256sum),
+    ("indent", indent),
+    ("nindent", nindent),
+    ("toJson", to_json),
+    ("toPrettyJson", to_pretty_json),
+    ("toYaml", to_yaml),
+    ("quote", quote),
+    ("squote", squote),
+];
+
+/// A shared snippet of template which other templates can include with `{{ template "NAME" . }}`.
+#[derive(Debug, Clone)]
+pub struct Partial {
+    pub name: String,
+    pub source_path: PathBuf,
+    pub content: String,
+}
+
+impl Partial {
+    /// Checks that the partial can be parsed on its own.
+    pub fn validate(&self) -> Result<(), errors::Error> {
+        parse(&self.content, &[])
+            .map(|_| ())
+            .map_err(|e| errors::user_with_internal(
+                format!("Could not parse the partial '{}' due to a problem in your template.", self.source_path.display()),
+                "Check that your partial is valid and review the internal error message for more information.",
+                e))
+    }
+}
+
+/// Loads the partials in `dir`, naming each of them after its path within the directory
+/// (without a `.tpl` extension), so `dir/nginx/tls.tpl` is named `nginx/tls`.
+#[instrument(level = "debug", name = "template.get_partials", err)]
+pub fn get_partials(dir: &Path) -> Result<Vec<Partial>, errors::Error> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut partials = Vec::new();
+    for entry in WalkDir::new(dir).follow_links(true).sort_by_file_name() {
+        let entry = entry.map_err(|err| errors::user_with_internal(
+            format!("Failed to read the list of partials in '{}'.", dir.display()),
+            "Read the internal error message and take the appropriate steps to resolve the issue.",
+            err))?;
+
+        if !entry.file_type().is_file() {
+            continue;
+        }
+
+        let relative_path = entry.path().strip_prefix(dir).unwrap_or(entry.path());
+        let relative_path = match relative_path.extension() {
+            Some(extension) if extension == "tpl" => relative_path.with_extension(""),
+            _ => relative_path.to_owned(),
+        };
+
+        partials.push(Partial {
+            name: relative_path
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/"),
+            source_path: entry.path().to_owned(),
+            content: std::fs::read_to_string(entry.path()).map_err(|err| errors::user_with_internal(
+                format!("Could not read the partial '{}'.", entry.path().display()),
+                "Make sure that the partial is a UTF-8 text file which buckle has permission to read.",
+                err))?,
+        });
+    }
+
+    Ok(partials)
+}
+
+/// Parses a template, making Buckle's helper functions and the given partials available to it.
+/// When several partials share a name, the last of them is used.
+pub fn parse(content: &str, partials: &[Partial]) -> Result<Template, TemplateError> {
+    let mut template = Template::default();
+    template.add_funcs(FUNCTIONS);
+    template.parse(content)?;
+
+    for partial in partials {
+        template.add_template(partial.name.as_str(), partial.content.as_str())?;
+    }
+
+    Ok(template)
+}
+
+/// Renders a template against the given context, with Buckle's helper functions and the given
+/// partials available to it.
+pub fn render(content: &str, partials: &[Partial], context: Value) -> Result<String, TemplateError> {
+    let template = parse(content, partials)?;
+    Ok(template.render(&Context::from(context))?)
+}
+
+/// A reference to a value which is not defined in the context a template is rendered against.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MissingKey {
+    pub line: usize,
+    pub key: String,
+}
+
+/// Finds the references to `.KEY` (and `$.KEY`) in a template which are not defined in `context`.
+///
+/// References within `range`, `with` and `define` blocks (where `.` no longer refers to the root
+/// of the context) are only checked when they are written as `$.KEY`, and the values passed to
+/// `default`, `coalesce` and `empty` are treated as intentionally optional.
+pub fn missing_keys(content: &str, context: &HashMap<String, Value>) -> Vec<MissingKey> {
+    let mut missing = Vec::new();
+
+    for (line, in_root, tokens) in scoped_actions(content) {
+        let mut position = 0;
+        let mut references = Vec::new();
+        required_references(&tokens, &mut position, &mut references);
+
+        for (is_root, chain) in references {
+            if !is_root && !in_root {
+                continue;
+            }
+
+            if let Some(key) = find_missing(context, &chain) {
+                missing.push(MissingKey { line, key });
+            }
+        }
+    }
+
+    missing
+}
+
+/// Finds the `{{ template "NAME" }}` actions in a template which include a partial that is
+/// neither one of `partials` nor defined by the template itself.
+pub fn missing_partials(content: &str, partials: &[Partial]) -> Vec<MissingKey> {
+    let actions = actions(content);
+    let defined: Vec<String> = actions
+        .iter()
+        .filter_map(|(_, action)| action.strip_prefix("define").or_else(|| action.strip_prefix("block")))
+        .filter_map(template_name)
+        .collect();
+
+    actions
+        .iter()
+        .filter_map(|(line, action)| action.strip_prefix("template").and_then(template_name).map(|name| (*line, name)))
+        .filter(|(_, name)| !defined.contains(name) && !partials.iter().any(|p| &p.name == name))
+        .map(|(line, key)| MissingKey { line, key })
+        .collect()
+}
+
+/// Lists the partials which a template includes with the root of its context (using
+/// `{{ template "NAME" . }}` outside of any `range` or `with` block, or `{{ template "NAME" $ }}`),
+/// along with those which they include in turn. When several partials share a name, the last of
+/// them is used (matching [`parse`]).
+pub fn included_partials<'a>(content: &str, partials: &'a [Partial]) -> Vec<&'a Partial> {
+    let mut included: Vec<&Partial> = Vec::new();
+    let mut pending = vec![content];
+
+    while let Some(content) = pending.pop() {
+        for (_, in_root, tokens) in scoped_actions(content) {
+            let name = match tokens.as_slice() {
+                [Token::Word(keyword), Token::Literal(name), Token::Word(arg)] if keyword == "template" && (arg == "$" || (arg == "." && in_root)) => {
+                    Some(name)
+                }
+                _ => None,
+            };
+
+            let partial = name.and_then(|name| partials.iter().rev().find(|p| &p.name == name));
+            if let Some(partial) = partial {
+                if !included.iter().any(|p| p.name == partial.name) {
+                    included.push(partial);
+                    pending.push(partial.content.as_str());
+                }
+            }
+        }
+    }
+
+    included
+}
+
+/// Lists the tokens of each action in a template (other than the `end` of each block), along with
+/// the line on which it starts and whether `.` still refers to the root of the context there.
+fn scoped_actions(content: &str) -> Vec<(usize, bool, Vec<Token>)> {
+    let mut scoped = Vec::new();
+    let mut blocks: Vec<bool> = Vec::new();
+
+    for (line, action) in actions(content) {
+        let tokens = tokenize(action);
+
+        let in_root = !blocks.iter().any(|changes_dot| *changes_dot);
+        match tokens.first() {
+            Some(Token::Word(word)) if word == "end" => {
+                blocks.pop();
+                continue;
+            }
+            Some(Token::Word(word)) if matches!(word.as_str(), "range" | "with" | "define" | "block") => blocks.push(true),
+            Some(Token::Word(word)) if word == "if" => blocks.push(false),
+            _ => {}
+        }
+
+        scoped.push((line, in_root, tokens));
+    }
+
+    scoped
+}
+
+/// Lists the actions (the trimmed contents of each `{{ }}`) in a template, along with the line
+/// on which each of them starts. Comments are skipped.
+fn actions(content: &str) -> Vec<(usize, &str)> {
+    let mut actions = Vec::new();
+    let mut offset = 0;
+
+    while let Some(start) = content[offset..].find("{{") {
+        let start = offset + start;
+        let end = match action_end(content, start + 2) {
+            Some(end) => end,
+            None => break,
+        };
+
+        let line = content[..start].matches('\n').count() + 1;
+        let action = content[start + 2..end].trim_start_matches('-').trim_end_matches('-').trim();
+        offset = end + 2;
+
+        if !action.starts_with("/*") {
+            actions.push((line, action));
+        }
+    }
+
+    actions
+}
+
+/// Finds the `}}` which closes the action starting at `offset`, ignoring any which appear within
+/// a comment or a string literal.
+fn action_end(content: &str, offset: usize) -> Option<usize> {
+    let rest = content[offset..].trim_start_matches('-').trim_start();
+    if rest.starts_with("/*") {
+        let comment_start = content.len() - rest.len();
+        let comment_end = comment_start + rest.find("*/")?;
+        return content[comment_end..].find("}}").map(|end| comment_end + end);
+    }
+
+    let bytes = content.as_bytes();
+    let mut quote: Option<u8> = None;
+    let mut i = offset;
+
+    while i < bytes.len() {
+        match (quote, bytes[i]) {
+            (Some(q), b'\\') if q != b'`' => i += 1,
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, c @ (b'"' | b'`' | b'\'')) => quote = Some(c),
+            (None, b'}') if bytes.get(i + 1) == Some(&b'}') => return Some(i),
+            (None, _) => {}
+        }
+
+        i += 1;
+    }
+
+    None
+}
+
+/// Reads the quoted template name which follows a `template`, `define` or `block` keyword.
+fn template_name(rest: &str) -> Option<String> {
+    if !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+
+    let rest = rest.trim_start();
+    let quote = rest.chars().next().filter(|c| matches!(c, '"' | '`'))?;
+    let rest = &rest[1..];
+    rest.find(quote).map(|end| rest[..end].to_string())
+}
+
+/// The parts of a template action which matter when looking for the values it references.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Literal(String),
+    Open,
+    Close,
+    Pipe,
+}
+
+/// Splits a template action into words, string literals, parentheses and pipes.
+fn tokenize(action: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    let mut chars = action.chars();
+
+    while let Some(c) = chars.next() {
+        if (c.is_whitespace() || matches!(c, '(' | ')' | '|' | '"' | '`' | '\'')) && !word.is_empty() {
+            tokens.push(Token::Word(std::mem::take(&mut word)));
+        }
+
+        match c {
+            '(' => tokens.push(Token::Open),
+            ')' => tokens.push(Token::Close),
+            '|' => tokens.push(Token::Pipe),
+            '"' | '`' | '\'' => {
+                let mut literal = String::new();
+                let mut escaped = false;
+                for inner in chars.by_ref() {
+                    match inner {
+                        _ if escaped => escaped = false,
+                        '\\' if c != '`' => escaped = true,
+                        inner if inner == c => break,
+                        _ => {}
+                    }
+
+                    literal.push(inner);
+                }
+
+                tokens.push(Token::Literal(literal));
+            }
+            c if c.is_whitespace() => {}
+            c => word.push(c),
+        }
+    }
+
+    if !word.is_empty() {
+        tokens.push(Token::Word(word));
+    }
+
+    tokens
+}
+
+/// Collects the references made by the pipeline starting at `position` which must be defined. The
+/// values passed to `default`, `coalesce` and `empty` (either as arguments, or by piping a single
+/// value into them) are left out, since those helpers accept missing values.
+fn required_references(tokens: &[Token], position: &mut usize, references: &mut Vec<(bool, String)>) {
+    // The references made by the previous command, which are optional if it is piped into one of the helpers.
+    let mut piped = Vec::new();
+
+    loop {
+        let (name, mut operands) = command(tokens, position);
+
+        let optional_from = match name.as_deref() {
+            Some("default") => Some(2),
+            Some("coalesce") | Some("empty") => Some(1),
+            _ => None,
+        };
+
+        match optional_from {
+            Some(optional_from) => {
+                piped.clear();
+                operands.truncate(optional_from);
+                references.extend(operands.into_iter().flatten());
+            }
+            None => {
+                references.append(&mut piped);
+                if operands.len() == 1 {
+                    piped = operands.remove(0);
+                } else {
+                    references.extend(operands.into_iter().flatten());
+                }
+            }
+        }
+
+        match tokens.get(*position) {
+            Some(Token::Pipe) => *position += 1,
+            _ => break,
+        }
+    }
+
+    references.append(&mut piped);
+}
+
+/// Reads the command starting at `position` (up to the next pipe or closing parenthesis),
+/// returning its name along with the references made by each of its operands.
+fn command(tokens: &[Token], position: &mut usize) -> (Option<String>, Vec<Vec<(bool, String)>>) {
+    let mut name = None;
+    let mut operands = Vec::new();
+
+    while let Some(token) = tokens.get(*position) {
+        match token {
+            Token::Pipe | Token::Close => break,
+            Token::Open => {
+                *position += 1;
+                let mut nested = Vec::new();
+                required_references(tokens, position, &mut nested);
+                operands.push(nested);
+            }
+            Token::Literal(_) => operands.push(Vec::new()),
+            // Variable declarations (like `$port := .PORT`) are followed by the command which provides their value.
+            Token::Word(word) if word == ":=" || word == "=" => {
+                name = None;
+                operands.clear();
+            }
+            Token::Word(word) => {
+                if operands.is_empty() {
+                    name = Some(word.clone());
+                }
+
+                operands.push(references(word));
+            }
+        }
+
+        *position += 1;
+    }
+
+    (name, operands)
+}
+
+/// Lists the field chains referenced by a word within a template action, along with whether each
+/// of them is relative to the root of the context (`$.KEY`) rather than the current value of `.`.
+fn references(word: &str) -> Vec<(bool, String)> {
+    let chars: Vec<char> = word.chars().collect();
+    let is_boundary = |i: usize| i == 0 || matches!(chars[i - 1], ' ' | '\t' | '\n' | '\r' | '(' | '|' | ',');
+    let is_ident = |c: char| c.is_alphanumeric() || c == '_';
+
+    let mut references = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        if chars[i] != '.' || i + 1 >= chars.len() || !(chars[i + 1].is_alphabetic() || chars[i + 1] == '_') {
+            i += 1;
+            continue;
+        }
+
+        let is_root = i > 0 && chars[i - 1] == '$' && is_boundary(i - 1);
+        if !is_root && !is_boundary(i) {
+            i += 1;
+            continue;
+        }
+
+        let mut end = i + 1;
+        while end < chars.len() && (is_ident(chars[end]) || (chars[end] == '.' && end + 1 < chars.len() && is_ident(chars[end + 1]))) {
+            end += 1;
+        }
+
+        references.push((is_root, chars[i + 1..end].iter().collect()));
+        i = end;
+    }
+
+    references
+}
+
+/// Walks a field chain through the context, returning the part of it which is not defined.
+fn find_missing(context: &HashMap<String, Value>, chain: &str) -> Option<String> {
+    let mut fields = context;
+    let mut path = Vec::new();
+
+    for segment in chain.split('.') {
+        path.push(segment);
+
+        match fields.get(segment) {
+            None => return Some(path.join(".")),
+            Some(Value::Object(inner)) | Some(Value::Map(inner)) => fields = inner,
+            Some(_) => return None,
+        }
+    }
+
+    None
+}
+
+fn exactly<'a>(name: &str, args: &'a [Value], count: usize) -> Result<&'a [Value], FuncError> {
+    if args.len() != count {
+        return Err(FuncError::ExactlyXArgs(name.to_string(), count));
+    }
+
+    Ok(args)
+}
+
+fn as_string(value: &Value) -> String {
+    match value {
+        Value::NoValue | Value::Nil => String::new(),
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn is_empty(value: &Value) -> bool {
+    match value {
+        Value::NoValue | Value::Nil => true,
+        Value::Bool(b) => !b,
+        Value::String(s) => s.is_empty(),
+        Value::Number(n) => n.as_f64() == Some(0.0),
+        Value::Array(items) => items.is_empty(),
+        Value::Object(fields) | Value::Map(fields) => fields.is_empty(),
+        Value::Function(_) => false,
+    }
+}
+
+/// Applies a function to a string argument, which is the last argument so that it can be piped.
+fn map_string(name: &str, args: &[Value], count: usize, f: impl FnOnce(&[Value], &str) -> Value) -> Result<Value, FuncError> {
+    let args = exactly(name, args, count)?;
+    let (input, options) = args.split_last().ok_or(FuncError::UnableToConvertFromValue)?;
+    Ok(f(options, &as_string(input)))
+}
+
+/// `default DEFAULT VALUE` returns `VALUE`, unless it is empty, in which case it returns `DEFAULT`.
+fn default(args: &[Value]) -> Result<Value, FuncError> {
+    let args = exactly("default", args, 2)?;
+    Ok(if is_empty(&args[1]) { args[0].clone() } else { args[1].clone() })
+}
+
+/// `empty VALUE` determines whether a value is missing, false, zero or empty.
+fn empty(args: &[Value]) -> Result<Value, FuncError> {
+    let args = exactly("empty", args, 1)?;
+    Ok(Value::Bool(is_empty(&args[0])))
+}
+
+/// `coalesce VALUE...` returns the first value which is not empty.
+fn coalesce(args: &[Value]) -> Result<Value, FuncError> {
+    Ok(args.iter().find(|v| !is_empty(v)).cloned().unwrap_or(Value::Nil))
+}
+
+fn upper(args: &[Value]) -> Result<Value, FuncError> {
+    map_string("upper", args, 1, |_, s| Value::String(s.to_uppercase()))
+}
+
+fn lower(args: &[Value]) -> Result<Value, FuncError> {
+    map_string("lower", args, 1, |_, s| Value::String(s.to_lowercase()))
+}
+
+/// `title STRING` capitalizes the first letter of each word.
+fn title(args: &[Value]) -> Result<Value, FuncError> {
+    map_string("title", args, 1, |_, s| {
+        let mut output = String::with_capacity(s.len());
+        let mut word_start = true;
+
+        for c in s.chars() {
+            if word_start {
+                output.extend(c.to_uppercase());
+            } else {
+                output.push(c);
+            }
+
+            word_start = c.is_whitespace();
+        }
+
+        Value::String(output)
+    })
+}
+
+fn trim(args: &[Value]) -> Result<Value, FuncError> {
+    map_string("trim", args, 1, |_, s| Value::String(s.trim().to_string()))
+}
+
+/// `trimPrefix PREFIX STRING` removes `PREFIX` from the start of `STRING`, if it is present.
+fn trim_prefix(args: &[Value]) -> Result<Value, FuncError> {
+    map_string("trimPrefix", args, 2, |opts, s| {
+        let prefix = as_string(&opts[0]);
+        Value::String(s.strip_prefix(prefix.as_str()).unwrap_or(s).to_string())
+    })
+}
+
+/// `trimSuffix SUFFIX STRING` removes `SUFFIX` from the end of `STRING`, if it is present.
+fn trim_suffix(args: &[Value]) -> Result<Value, FuncError> {
+    map_string("trimSuffix", args, 2, |opts, s| {
+        let suffix = as_string(&opts[0]);
+        Value::String(s.strip_suffix(suffix.as_str()).unwrap_or(s).to_string())
+    })
+}
+
+/// `replace OLD NEW STRING` replaces every occurrence of `OLD` with `NEW`.
+fn replace(args: &[Value]) -> Result<Value, FuncError> {
+    map_string("replace", args, 3, |opts, s| {
+        Value::String(s.replace(&as_string(&opts[0]), &as_string(&opts[1])))
+    })
+}
+
+/// `contains SUBSTRING STRING` determines whether `STRING` contains `SUBSTRING`.
+fn contains(args: &[Value]) -> Result<Value, FuncError> {
+    map_string("contains", args, 2, |opts, s| Value::Bool(s.contains(&as_string(&opts[0]))))
+}
+
+fn has_prefix(args: &[Value]) -> Result<Value, FuncError> {
+    map_string("hasPrefix", args, 2, |opts, s| Value::Bool(s.starts_with(&as_string(&opts[0]))))
+}
+
+fn has_suffix(args: &[Value]) -> Result<Value, FuncError> {
+    map_string("hasSuffix", args, 2, |opts, s| Value::Bool(s.ends_with(&as_string(&opts[0]))))
+}
+
+/// `split SEPARATOR STRING` splits `STRING` into a list wherever `SEPARATOR` appears.
+fn split(args: &[Value]) -> Result<Value, FuncError> {
+    map_string("split", args, 2, |opts, s| {
+        Value::Array(s.split(as_string(&opts[0]).as_str()).map(Value::from).collect())
+    })
+}
+
+/// `join SEPARATOR LIST` joins the items in `LIST` with `SEPARATOR` between each of them.
+fn join(args: &[Value]) -> Result<Value, FuncError> {
+    let args = exactly("join", args, 2)?;
+    let separator = as_string(&args[0]);
+
+    let items: Vec<String> = match &args[1] {
+        Value::Array(items) => items.iter().map(as_string).collect(),
+        other => vec![as_string(other)],
+    };
+
+    Ok(Value::String(items.join(&separator)))
+}
+
+fn b64enc(args: &[Value]) -> Result<Value, FuncError> {
+    map_string("b64enc", args, 1, |_, s| Value::String(BASE64.encode(s)))
+}
+
+fn b64dec(args: &[Value]) -> Result<Value, FuncError> {
+    let args = exactly("b64dec", args, 1)?;
+
+    let decoded = BASE64
+        .decode(as_string(&args[0]).trim())
+        .map_err(|e| FuncError::Generic(format!("b64dec could not decode its input: {e}")))?;
+
+    String::from_utf8(decoded)
+        .map(Value::String)
+        .map_err(|_| FuncError::Generic("b64dec decoded a value which is not valid UTF-8".to_string()))
+}
+
+fn sha256sum(args: &[Value]) -> Result<Value, FuncError> {
+    map_string("sha256sum", args, 1, |_, s| Value::String(super::file::content_hash(s.as_bytes())))
+}
+
+/// `indent WIDTH STRING` indents every line of `STRING` by `WIDTH` spaces.
+fn indent(args: &[Value]) -> Result<Value, FuncError> {
+    let args = exactly("indent", args, 2)?;
+
+    let width = match &args[0] {
+        Value::Number(n) => n.as_u64().ok_or(FuncError::UnableToConvertFromValue)?,
+        other => as_string(other).trim().parse().map_err(|_| FuncError::UnableToConvertFromValue)?,
+    };
+
+    let padding = " ".repeat(width as usize);
+    let content = as_string(&args[1]);
+
+    Ok(Value::String(format!("{}{}", padding, content.replace('\n', &format!("\n{padding}")))))
+}
+
+/// `nindent WIDTH STRING` is the same as `indent`, but starts with a new line.
+fn nindent(args: &[Value]) -> Result<Value, FuncError> {
+    Ok(Value::String(format!("\n{}", as_string(&indent(args)?))))
+}
+
+fn to_json(args: &[Value]) -> Result<Value, FuncError> {
+    let args = exactly("toJson", args, 1)?;
+    serde_json::to_string(&json_value(&args[0]))
+        .map(Value::String)
+        .map_err(|e| FuncError::Generic(e.to_string()))
+}
+
+fn to_pretty_json(args: &[Value]) -> Result<Value, FuncError> {
+    let args = exactly("toPrettyJson", args, 1)?;
+    serde_json::to_string_pretty(&json_value(&args[0]))
+        .map(Value::String)
+        .map_err(|e| FuncError::Generic(e.to_string()))
+}
+
+fn to_yaml(args: &[Value]) -> Result<Value, FuncError> {
+    let args = exactly("toYaml", args, 1)?;
+    serde_yaml::to_string(&json_value(&args[0]))
+        .map(|yaml| Value::String(yaml.trim_end().to_string()))
+        .map_err(|e| FuncError::Generic(e.to_string()))
+}
+
+/// `quote VALUE...` wraps each value in double quotes (escaping it), separated by spaces.
+fn quote(args: &[Value]) -> Result<Value, FuncError> {
+    let quoted: Vec<String> = args
+        .iter()
+        .filter(|v| !matches!(v, Value::NoValue | Value::Nil))
+        .map(|v| serde_json::Value::String(as_string(v)).to_string())
+        .collect();
+
+    Ok(Value::String(quoted.join(" ")))
+}
+
+/// `squote VALUE...` wraps each value in single quotes, separated by spaces.
+fn squote(args: &[Value]) -> Result<Value, FuncError> {
+    let quoted: Vec<String> = args
+        .iter()
+        .filter(|v| !matches!(v, Value::NoValue | Value::Nil))
+        .map(|v| format!("'{}'", as_string(v)))
+        .collect();
+
+    Ok(Value::String(quoted.join(" ")))
+}
+
+fn json_value(value: &Value) -> serde_json::Value {
+    match value {
+        Value::NoValue | Value::Nil | Value::Function(_) => serde_json::Value::Null,
+        Value::Bool(b) => serde_json::Value::Bool(*b),
+        Value::String(s) => serde_json::Value::String(s.clone()),
+        Value::Number(n) => match (n.as_i64(), n.as_u64(), n.as_f64()) {
+            (Some(i), _, _) => i.into(),
+            (_, Some(u), _) => u.into(),
+            (_, _, Some(f)) => serde_json::Number::from_f64(f)
+                .map(serde_json::Value::Number)
+                .unwrap_or(serde_json::Value::Null),
+            _ => serde_json::Value::Null,
+        },
+        Value::Array(items) => serde_json::Value::Array(items.iter().map(json_value).collect()),
+        Value::Object(fields) | Value::Map(fields) => serde_json::Value::Object(
+            fields
+                .iter()
+                .map(|(k, v)| (k.clone(), json_value(v)))
+                .collect(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(f: Func, args: Vec<Value>) -> Value {
+        f(&args).expect("the function should succeed")
+    }
+
+    fn s(value: &str) -> Value {
+        Value::from(value)
+    }
+
+    #[test]
+    fn default_values() {
+        assert_eq!(call(default, vec![s("fallback"), Value::NoValue]), s("fallback"));
+        assert_eq!(call(default, vec![s("fallback"), s("")]), s("fallback"));
+        assert_eq!(call(default, vec![s("fallback"), s("set")]), s("set"));
+        assert!(default(&[s("only one")]).is_err());
+    }
+
+    #[test]
+    fn empty_values() {
+        assert_eq!(call(empty, vec![Value::Nil]), Value::Bool(true));
+        assert_eq!(call(empty, vec![Value::Array(vec![])]), Value::Bool(true));
+        assert_eq!(call(empty, vec![s("x")]), Value::Bool(false));
+    }
+
+    #[test]
+    fn coalesce_values() {
+        assert_eq!(call(coalesce, vec![Value::NoValue, s(""), s("first"), s("second")]), s("first"));
+        assert_eq!(call(coalesce, vec![Value::NoValue]), Value::Nil);
+    }
+
+    #[test]
+    fn case() {
+        assert_eq!(call(upper, vec![s("Hello")]), s("HELLO"));
+        assert_eq!(call(lower, vec![s("Hello")]), s("hello"));
+        assert_eq!(call(title, vec![s("hello big world")]), s("Hello Big World"));
+    }
+
+    #[test]
+    fn trimming() {
+        assert_eq!(call(trim, vec![s("  padded \n")]), s("padded"));
+        assert_eq!(call(trim_prefix, vec![s("v"), s("v1.2.3")]), s("1.2.3"));
+        assert_eq!(call(trim_suffix, vec![s(".conf"), s("nginx.conf")]), s("nginx"));
+        assert_eq!(call(trim_suffix, vec![s(".conf"), s("nginx")]), s("nginx"));
+    }
+
+    #[test]
+    fn searching() {
+        assert_eq!(call(replace, vec![s("-"), s("_"), s("a-b-c")]), s("a_b_c"));
+        assert_eq!(call(contains, vec![s("ell"), s("hello")]), Value::Bool(true));
+        assert_eq!(call(has_prefix, vec![s("he"), s("hello")]), Value::Bool(true));
+        assert_eq!(call(has_suffix, vec![s("he"), s("hello")]), Value::Bool(false));
+    }
+
+    #[test]
+    fn split_and_join() {
+        let parts = call(split, vec![s(","), s("a,b,c")]);
+        assert_eq!(parts, Value::Array(vec![s("a"), s("b"), s("c")]));
+        assert_eq!(call(join, vec![s(" "), parts]), s("a b c"));
+    }
+
+    #[test]
+    fn base64() {
+        assert_eq!(call(b64enc, vec![s("buckle")]), s("YnVja2xl"));
+        assert_eq!(call(b64dec, vec![s("YnVja2xl")]), s("buckle"));
+        assert!(b64dec(&[s("not base64!")]).is_err());
+    }
+
+    #[test]
+    fn hashing() {
+        assert_eq!(
+            call(sha256sum, vec![s("buckle")]),
+            s(&super::super::file::content_hash(b"buckle"))
+        );
+    }
+
+    #[test]
+    fn indentation() {
+        assert_eq!(call(indent, vec![Value::from(2_i64), s("a\nb")]), s("  a\n  b"));
+        assert_eq!(call(nindent, vec![Value::from(2_i64), s("a\nb")]), s("\n  a\n  b"));
+    }
+
+    #[test]
+    fn serialization() {
+        let mut fields = HashMap::new();
+        fields.insert("name".to_string(), s("buckle"));
+        fields.insert("ports".to_string(), Value::Array(vec![Value::from(80_i64)]));
+        let object = Value::Object(fields);
+
+        assert_eq!(call(to_json, vec![object.clone()]), s(r#"{"name":"buckle","ports":[80]}"#));
+        assert_eq!(
+            call(to_pretty_json, vec![object.clone()]),
+            s("{\n  \"name\": \"buckle\",\n  \"ports\": [\n    80\n  ]\n}")
+        );
+        assert_eq!(call(to_yaml, vec![object]), s("name: buckle\nports:\n- 80"));
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(call(quote, vec![s("say \"hi\""), s("b")]), s(r#""say \"hi\"" "b""#));
+        assert_eq!(call(squote, vec![s("a"), Value::Nil]), s("'a'"));
+    }
+
+    #[test]
+    fn partials() {
+        let temp = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(temp.path().join("nginx")).unwrap();
+        std::fs::write(temp.path().join("logging-block.tpl"), "log {{ .LEVEL }}").unwrap();
+        std::fs::write(temp.path().join("nginx").join("tls.tpl"), "ssl on").unwrap();
+
+        let partials = get_partials(temp.path()).expect("the partials should be loaded");
+        let names: Vec<&str> = partials.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, vec!["logging-block", "nginx/tls"]);
+
+        let content = "{{ define \"local\" }}{{ end }}{{ template \"logging-block\" . }}\n{{ template \"local\" }}{{ template \"missing\" . }}";
+        assert_eq!(
+            missing_partials(content, &partials),
+            vec![MissingKey { line: 2, key: "missing".to_string() }]
+        );
+
+        assert!(get_partials(&temp.path().join("absent")).expect("a missing directory has no partials").is_empty());
+    }
+
+    #[test]
+    fn included_partials_scope() {
+        let partial = |name: &str, content: &str| Partial {
+            name: name.to_string(),
+            source_path: PathBuf::from(format!("{}.tpl", name)),
+            content: content.to_string(),
+        };
+
+        let partials = vec![
+            partial("header", "{{ template \"banner\" . }}"),
+            partial("banner", "{{ template \"header\" . }}{{ .NAME }}"),
+            partial("item", "{{ .name }}"),
+            partial("footer", "{{ .FOOTER }}"),
+            partial("unused", "{{ .UNUSED }}"),
+        ];
+
+        let content = "{{ template \"header\" . }}{{ range .items }}{{ template \"item\" . }}{{ template \"footer\" $ }}{{ end }}";
+        let names: Vec<&str> = included_partials(content, &partials).iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, vec!["header", "footer", "banner"]);
+    }
+
+    #[test]
+    fn missing_keys_strict() {
+        let mut db = HashMap::new();
+        db.insert("host".to_string(), s("localhost"));
+
+        let mut context = HashMap::new();
+        context.insert("IP_ADDRESS".to_string(), s("10.0.0.1"));
+        context.insert("users".to_string(), Value::Array(vec![]));
+        context.insert("db".to_string(), Value::Object(db));
+
+        let content = [
+            "listen {{ .IP_ADDRESS }}",
+            "listen {{ .IP_ADRESS }}",
+            "{{ range .users }}{{ .name }} {{ $.MISSING_ROOT }}{{ end }}",
+            "{{ .db.host }} {{ .db.port }} {{ \".NOT_A_REFERENCE\" }}",
+            "{{ .PORT | default \"8080\" }} {{ if .DEBUG }}debug{{ end }}",
+        ]
+        .join("\n");
+
+        assert_eq!(
+            missing_keys(&content, &context),
+            vec![
+                MissingKey { line: 2, key: "IP_ADRESS".to_string() },
+                MissingKey { line: 3, key: "MISSING_ROOT".to_string() },
+                MissingKey { line: 4, key: "db.port".to_string() },
+                MissingKey { line: 5, key: "DEBUG".to_string() },
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_keys_optional_operands() {
+        let mut context = HashMap::new();
+        context.insert("HOST".to_string(), s("localhost"));
+
+        let content = [
+            "{{ printf \"%s:%s\" .HOTS (default \"80\" .PORT) }}",
+            "{{ $port := .PORT | default \"80\" }}{{ if not (empty .DEBUG) }}{{ end }}",
+            "{{ coalesce .A .B \"c\" | upper }} {{ default .FALLBACK .VALUE }} {{ .VALUE | printf \"%s\" | default \"x\" }}",
+        ]
+        .join("\n");
+
+        assert_eq!(
+            missing_keys(&content, &context),
+            vec![
+                MissingKey { line: 1, key: "HOTS".to_string() },
+                MissingKey { line: 3, key: "FALLBACK".to_string() },
+                MissingKey { line: 3, key: "VALUE".to_string() },
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_keys_braces_in_strings() {
+        let context = HashMap::new();
+        let content = "{{ printf \"}}\" .MISSING }} {{/* {{ .COMMENTED }} \" */}} {{ .ALSO_MISSING }}";
+
+        assert_eq!(
+            missing_keys(content, &context),
+            vec![
+                MissingKey { line: 1, key: "MISSING".to_string() },
+                MissingKey { line: 1, key: "ALSO_MISSING".to_string() },
+            ]
+        );
+    }
+}
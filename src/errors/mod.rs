@@ -14,6 +14,20 @@ impl Error {
     pub fn is_system(&self) -> bool {
         self.0.is(human_errors::Kind::System)
     }
+
+    /// Determines whether this error was caused by an operation exceeding its time limit.
+    pub fn is_timeout(&self) -> bool {
+        let mut source = std::error::Error::source(self);
+        while let Some(err) = source {
+            if err.is::<Timeout>() {
+                return true;
+            }
+
+            source = err.source();
+        }
+
+        false
+    }
 }
 
 impl std::fmt::Display for Error {
@@ -62,8 +76,108 @@ pub fn system_with_internal(
     human_errors::wrap_system(err, message.into(), leak_advice(advice)).into()
 }
 
+/// Marks an operation which did not complete within its time limit. The `details` (usually the
+/// output of the operation) are redacted, since they are included in the error's telemetry.
+pub fn timeout(
+    message: impl Into<String>,
+    advice: impl Into<String>,
+    limit: std::time::Duration,
+    details: impl Into<String>,
+) -> Error {
+    let details = crate::core::redact::redact(&details.into());
+    user_with_internal(message, advice, Timeout { limit, details })
+}
+
+/// The internal error used to mark an operation which did not complete within its time limit.
+#[derive(Debug)]
+pub struct Timeout {
+    limit: std::time::Duration,
+    details: String,
+}
+
+impl std::fmt::Display for Timeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Timed out after {}s.\n{}", self.limit.as_secs_f64(), self.details)
+    }
+}
+
+impl std::error::Error for Timeout {}
+
+/// Combines several errors into one, so that every failure can be reported together rather than
+/// only the last of them. Each failure is also recorded as an exception event on the current span.
+pub fn composite(message: impl Into<String>, advice: impl Into<String>, mut errors: Vec<Error>) -> Error {
+    if errors.len() == 1 {
+        return errors.remove(0);
+    }
+
+    for error in errors.iter() {
+        tracing::error!(
+            exception.message = %crate::core::redact::redact(&error.description()),
+            exception.details = %crate::core::redact::redact(&Composite::details(error)),
+            "{}",
+            crate::core::redact::redact(&error.description())
+        );
+    }
+
+    let is_system = errors.iter().any(|e| e.is_system());
+    let composite = Composite { errors };
+
+    if is_system {
+        system_with_internal(message, advice, composite)
+    } else {
+        user_with_internal(message, advice, composite)
+    }
+}
+
+/// The internal error used to hold each of the failures which make up a [composite] error.
+#[derive(Debug)]
+pub struct Composite {
+    errors: Vec<Error>,
+}
+
+impl Composite {
+    /// Describes the causes of an error (beyond its own description), one per line.
+    fn details(error: &Error) -> String {
+        let description = error.description();
+        let mut details = Vec::new();
+
+        let mut source = std::error::Error::source(error);
+        while let Some(err) = source {
+            let message = err.to_string();
+            if message != description && !details.contains(&message) {
+                details.push(message);
+            }
+
+            source = err.source();
+        }
+
+        details.join("\n")
+    }
+}
+
+impl std::fmt::Display for Composite {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, error) in self.errors.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+
+            let prefix = format!("{}. ", i + 1);
+            write!(f, "{prefix}{}", error.description())?;
+
+            for line in Composite::details(error).lines() {
+                write!(f, "\n{:indent$}{line}", "", indent = prefix.len())?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for Composite {}
+
 pub fn detailed_message(message: impl Into<String>) -> std::io::Error {
-    std::io::Error::other(message.into())
+    std::io::Error::other(crate::core::redact::redact(&message.into()))
 }
 
 #[cfg(unix)]
@@ -57,7 +57,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     });
 }
 
-#[instrument(name = "app.host", fields(otel.name="buckle", otel.kind=?SpanKind::Client, exception=field::Empty, host.hostname=field::Empty, exit_code=field::Empty), skip(app, commands, matches), ret, err)]
+#[instrument(name = "app.host", fields(otel.name="buckle", otel.kind=?SpanKind::Client, exception=field::Empty, error.kind=field::Empty, host.hostname=field::Empty, buckle.run_id=field::Empty, exit_code=field::Empty), skip(app, commands, matches), ret, err)]
 fn host(
     app: clap::Command,
     commands: Vec<Arc<dyn CommandRunnable>>,
@@ -69,7 +69,8 @@ fn host(
 
     Span::current()
         .record("otel.name", command_name)
-        .record("host.hostname", gethostname().to_string_lossy().trim());
+        .record("host.hostname", gethostname().to_string_lossy().trim())
+        .record("buckle.run_id", crate::core::context::run_id());
 
     match run(commands, matches) {
         Ok(status@0) => {
@@ -90,18 +91,27 @@ fn host(
             Ok(status)
         }
         Err(error) => {
-            println!("{error}");
+            println!("{}", crate::core::redact::redact(&error.to_string()));
 
             error!("Exiting with status code {}", 1);
             Span::current()
                 .record("exit_code", 1_u32);
 
             if error.is_system() {
-                Span::current().record("exception", &field::display(&error));
+                Span::current().record("exception", crate::core::redact::redact(&error.to_string()));
             } else {
-                Span::current().record("exception", &error.description());
+                Span::current().record("exception", crate::core::redact::redact(&error.description()));
             }
 
+            Span::current().record(
+                "error.kind",
+                match &error {
+                    e if e.is_timeout() => "timeout",
+                    e if e.is_system() => "system",
+                    _ => "user",
+                },
+            );
+
             Err(error)
         }
     }
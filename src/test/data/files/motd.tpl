@@ -0,0 +1 @@
+{{ template "motd" . }}
\ No newline at end of file
@@ -0,0 +1 @@
+# This file is managed by buckle.
@@ -0,0 +1 @@
+{{ template "header" . }}Welcome to {{ .HOSTNAME }}.